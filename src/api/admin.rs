@@ -0,0 +1,166 @@
+use std::sync::OnceLock;
+
+use anyhow::{Result, anyhow};
+use chrono::DateTime;
+use tokio::net::UnixStream;
+
+use crate::{
+    api::http::{self, Request},
+    data,
+    worker::{
+        WorkerRequest,
+        admin::{PurgeRequest, PurgeResult, Stats},
+    },
+};
+
+pub enum Action {
+    Stats,
+    ForceRoute(Option<String>),
+    Pause,
+    Resume,
+    Purge(PurgeRequest),
+}
+
+impl Action {
+    pub fn parse(req: &Request) -> Result<Option<Self>> {
+        let action = match (req.method, req.path) {
+            ("GET", "/admin/stats") => Action::Stats,
+            ("POST", "/admin/route") => {
+                let processor = req.param("processor").filter(|p| !p.is_empty());
+                Action::ForceRoute(processor.map(|p| p.into_owned()))
+            }
+            ("POST", "/admin/pause") => Action::Pause,
+            ("POST", "/admin/resume") => Action::Resume,
+            ("POST", "/admin/purge" | "/purge-payments") => Action::Purge(purge_request(req)?),
+            _ => return Ok(None),
+        };
+
+        Ok(Some(action))
+    }
+}
+
+/// Reads the optional `from`/`to` range and the `snapshot`/`dryRun` flags.
+fn purge_request(req: &Request) -> Result<PurgeRequest> {
+    let from = timestamp(req, "from")?;
+    let to = timestamp(req, "to")?;
+
+    let range = match (from, to) {
+        (None, None) => None,
+        (from, to) => Some((from.unwrap_or(i64::MIN), to.unwrap_or(i64::MAX))),
+    };
+
+    let flag = |name| req.param(name).is_some_and(|v| v == "true");
+
+    Ok(PurgeRequest {
+        range,
+        snapshot: flag("snapshot"),
+        dry_run: flag("dryRun"),
+        actor: req.header("X-Admin-User").map(str::to_string),
+    })
+}
+
+fn timestamp(req: &Request, name: &str) -> Result<Option<i64>> {
+    let Some(value) = req.param(name) else {
+        return Ok(None);
+    };
+
+    let date = DateTime::parse_from_rfc3339(&value)
+        .map_err(|err| anyhow!("invalid {name} {value:?}: {err}"))?;
+
+    Ok(Some(date.timestamp_micros()))
+}
+
+/// `ADMIN_TOKEN`, read once; unset or empty closes the admin surface.
+fn token() -> Option<&'static str> {
+    static TOKEN: OnceLock<Option<String>> = OnceLock::new();
+
+    TOKEN
+        .get_or_init(|| std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()))
+        .as_deref()
+}
+
+pub fn enabled() -> bool {
+    token().is_some()
+}
+
+/// Whether `X-Admin-Token` or `X-Rinha-Token` matches `ADMIN_TOKEN`.
+pub fn authorized(req: &Request) -> bool {
+    let given = req.header("X-Admin-Token").or(req.header("X-Rinha-Token"));
+
+    matches(given, token())
+}
+
+fn matches(given: Option<&str>, token: Option<&str>) -> bool {
+    match (given, token) {
+        (Some(given), Some(token)) => constant_time_eq(given.as_bytes(), token.as_bytes()),
+        _ => false,
+    }
+}
+
+/// Takes as long wherever the inputs differ, so the token can not be guessed
+/// a byte at a time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub async fn handle(
+    client: &mut UnixStream,
+    worker: &mut UnixStream,
+    buf: &mut [u8],
+    action: Action,
+) -> Result<()> {
+    match action {
+        Action::Stats => {
+            data::send(WorkerRequest::Stats, buf, worker).await?;
+            let stats: Stats = data::recv(worker).await?;
+
+            let body = serde_json::to_vec(&stats)?;
+            http::respond(client, "200 OK", &body).await
+        }
+        Action::ForceRoute(processor) => {
+            data::send(WorkerRequest::ForceRoute(processor), buf, worker).await?;
+
+            match data::recv(worker).await? {
+                true => http::respond(client, "200 OK", b"").await,
+                false => http::respond_error(client, "404 Not Found", "unknown processor").await,
+            }
+        }
+        Action::Pause => {
+            data::send(WorkerRequest::Pause, buf, worker).await?;
+            http::respond(client, "200 OK", b"").await
+        }
+        Action::Resume => {
+            data::send(WorkerRequest::Resume, buf, worker).await?;
+            http::respond(client, "200 OK", b"").await
+        }
+        Action::Purge(req) => {
+            data::send(WorkerRequest::Purge(req), buf, worker).await?;
+            let result: Result<PurgeResult, String> = data::recv(worker).await?;
+
+            match result {
+                Ok(result) => {
+                    let body = serde_json::to_vec(&result)?;
+                    http::respond(client, "200 OK", &body).await
+                }
+                Err(err) => http::respond_error(client, "500 Internal Server Error", &err).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret1"));
+        assert!(!constant_time_eq(b"", b"x"));
+
+        assert!(matches(Some("secret"), Some("secret")));
+        assert!(!matches(Some(""), None));
+        assert!(!matches(None, Some("secret")));
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::{Result, anyhow};
+use tokio::{io::AsyncWriteExt, net::UnixStream};
+
+use crate::{
+    api::{
+        http::Request,
+        summary::{self, Query, Scope},
+    },
+    data,
+    worker::WorkerRequest,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Format {
+    Csv,
+    Ndjson,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Export {
+    pub query: Query,
+    pub format: Format,
+}
+
+/// Reads `from`/`to` like the summary and `format`, `csv` when missing.
+pub fn parse(req: &Request, scope: Scope) -> Result<Export> {
+    let format = match req.param("format").as_deref() {
+        None | Some("csv") => Format::Csv,
+        Some("ndjson") => Format::Ndjson,
+        Some(format) => return Err(anyhow!("invalid format {format:?}")),
+    };
+
+    Ok(Export {
+        query: summary::query(req, scope),
+        format,
+    })
+}
+
+/// Relays the records the worker sends chunk by chunk as a chunked response,
+/// so the range never has to fit in memory.
+pub async fn export(
+    client: &mut UnixStream,
+    worker: &mut UnixStream,
+    buf: &mut [u8],
+    export: Export,
+) -> Result<()> {
+    let head = match export.format {
+        Format::Csv => {
+            "Content-Type: text/csv\r\nContent-Disposition: attachment; filename=\"payments.csv\""
+        }
+        Format::Ndjson => "Content-Type: application/x-ndjson",
+    };
+
+    data::send(WorkerRequest::Export(export), buf, worker).await?;
+
+    let head = format!("HTTP/1.1 200 OK\r\n{head}\r\nTransfer-Encoding: chunked\r\n\r\n");
+    client.write_all(head.as_bytes()).await?;
+
+    while let Some(chunk) = data::recv::<Option<Vec<u8>>, _>(worker).await? {
+        if chunk.is_empty() {
+            continue;
+        }
+
+        client
+            .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+            .await?;
+        client.write_all(&chunk).await?;
+        client.write_all(b"\r\n").await?;
+    }
+
+    client.write_all(b"0\r\n\r\n").await?;
+
+    Ok(())
+}
@@ -0,0 +1,230 @@
+use std::{borrow::Cow, time::Duration};
+
+use anyhow::{Result, anyhow};
+use httparse::{Header, Status};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+pub const MAX_HEADERS: usize = 32;
+
+pub struct Request<'b, 'h> {
+    pub method: &'b str,
+    pub path: &'b str,
+    pub query: &'b str,
+    pub headers: &'h [Header<'b>],
+    pub body_start: usize,
+}
+
+/// Parses the request line and headers, `None` when they are incomplete.
+pub fn parse<'b, 'h>(
+    buf: &'b [u8],
+    headers: &'h mut [Header<'b>],
+) -> Result<Option<Request<'b, 'h>>> {
+    let mut req = httparse::Request::new(headers);
+
+    let body_start = match req.parse(buf)? {
+        Status::Complete(n) => n,
+        Status::Partial => return Ok(None),
+    };
+
+    let method = req.method.ok_or_else(|| anyhow!("missing method"))?;
+    let target = req.path.ok_or_else(|| anyhow!("missing path"))?;
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    Ok(Some(Request {
+        method,
+        path,
+        query,
+        headers: req.headers,
+        body_start,
+    }))
+}
+
+/// Keeps reading after the first `n` bytes until the headers and the whole
+/// `Content-Length` body are in `buf`, the buffer is full or the peer closes.
+pub async fn read_rest<S: AsyncReadExt + Unpin>(
+    socket: &mut S,
+    buf: &mut [u8],
+    mut n: usize,
+) -> Result<usize> {
+    loop {
+        let expected = {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+
+            match parse(&buf[..n], &mut headers) {
+                Ok(Some(req)) => req.body_start.saturating_add(req.content_length()),
+                Ok(None) => usize::MAX,
+                // malformed, left for the caller to answer
+                Err(_) => return Ok(n),
+            }
+        };
+
+        if n >= expected || n == buf.len() {
+            return Ok(n);
+        }
+
+        let read = socket.read(&mut buf[n..]).await?;
+
+        if read == 0 {
+            return Ok(n);
+        }
+
+        n += read;
+    }
+}
+
+/// The whole `content_length` body, whose first bytes were already read
+/// into `buf[body_start..n]`.
+pub async fn read_body<S: AsyncReadExt + Unpin>(
+    socket: &mut S,
+    buf: &[u8],
+    n: usize,
+    body_start: usize,
+    content_length: usize,
+) -> Result<Vec<u8>> {
+    let read = &buf[body_start..n.min(body_start + content_length)];
+
+    let mut body = Vec::with_capacity(content_length);
+    body.extend_from_slice(read);
+    body.resize(content_length, 0);
+
+    socket.read_exact(&mut body[read.len()..]).await?;
+
+    Ok(body)
+}
+
+impl Request<'_, '_> {
+    pub fn content_length(&self) -> usize {
+        self.header("Content-Length")
+            .and_then(|len| len.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+    }
+
+    pub fn param(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find_map(|(k, v)| (k == name).then(|| percent_decode(v)))
+    }
+}
+
+fn percent_decode(value: &str) -> Cow<'_, str> {
+    if !value.contains(['%', '+']) {
+        return Cow::Borrowed(value);
+    }
+
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+pub async fn respond<S: AsyncWriteExt + Unpin>(
+    socket: &mut S,
+    status: &str,
+    body: &[u8],
+) -> Result<()> {
+    let content_type = match body.is_empty() {
+        true => "",
+        false => "Content-Type: application/json\r\n",
+    };
+
+    let head = format!(
+        "HTTP/1.1 {status}\r\n{content_type}Content-Length: {}\r\n\r\n",
+        body.len()
+    );
+
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body).await?;
+
+    Ok(())
+}
+
+/// `429` telling the client to retry after `wait`, rounded up to seconds.
+pub async fn respond_rate_limited<S: AsyncWriteExt + Unpin>(
+    socket: &mut S,
+    wait: Duration,
+) -> Result<()> {
+    let body = serde_json::json!({ "error": "rate limit exceeded" }).to_string();
+    let retry_after = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+
+    let head = format!(
+        "HTTP/1.1 429 Too Many Requests\r\nRetry-After: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+        retry_after.max(1),
+        body.len()
+    );
+
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await?;
+
+    Ok(())
+}
+
+pub async fn respond_error<S: AsyncWriteExt + Unpin>(
+    socket: &mut S,
+    status: &str,
+    error: &str,
+) -> Result<()> {
+    let body = serde_json::json!({ "error": error }).to_string();
+
+    respond(socket, status, body.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let input = b"POST /admin/route?processor=fallback&x=1 HTTP/1.1\r\nHost: localhost\r\nX-Admin-Token: secret\r\nContent-Length: 2\r\n\r\n{}";
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let req = parse(input, &mut headers)
+            .expect("parse")
+            .expect("complete");
+
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/admin/route");
+        assert_eq!(req.param("processor").as_deref(), Some("fallback"));
+        assert_eq!(req.param("y"), None);
+        assert_eq!(req.header("x-admin-token"), Some("secret"));
+        assert_eq!(&input[req.body_start..], b"{}");
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        assert!(
+            parse(b"GET /payments HTTP/1.1\r\nHo", &mut headers)
+                .expect("parse")
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use anyhow::{Result, anyhow};
+
+use crate::api::http::Request;
+
+/// `MERCHANT_API_KEYS`, as `key=merchant,...`.
+pub fn api_keys() -> &'static HashMap<String, String> {
+    static KEYS: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+    KEYS.get_or_init(|| {
+        let keys = std::env::var("MERCHANT_API_KEYS").unwrap_or_default();
+
+        keys.split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, merchant)| (key.trim().to_string(), merchant.trim().to_string()))
+            .collect()
+    })
+}
+
+/// Merchant a request acts for: the one owning its `X-Api-Key`, `None`
+/// without a known key. An `X-Merchant-Id` must name that same merchant.
+pub fn of(req: &Request) -> Result<Option<String>> {
+    resolve(req, api_keys())
+}
+
+fn resolve(req: &Request, keys: &HashMap<String, String>) -> Result<Option<String>> {
+    let owner = req.header("X-Api-Key").and_then(|key| keys.get(key));
+
+    match (owner, req.header("X-Merchant-Id")) {
+        (owner, None) => Ok(owner.cloned()),
+        (Some(owner), Some(merchant)) if owner == merchant => Ok(Some(owner.clone())),
+        (_, Some(merchant)) => Err(anyhow!(
+            "X-Merchant-Id {merchant:?} does not match the API key"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::http;
+
+    fn merchant(input: &[u8]) -> Result<Option<String>> {
+        let keys = HashMap::from([("k1".to_string(), "acme".to_string())]);
+
+        let mut headers = [httparse::EMPTY_HEADER; http::MAX_HEADERS];
+        let req = http::parse(input, &mut headers)
+            .expect("parse")
+            .expect("complete");
+
+        resolve(&req, &keys)
+    }
+
+    #[test]
+    fn test_resolve() {
+        let of = |headers: &str| {
+            merchant(format!("GET /payments-summary HTTP/1.1\r\n{headers}\r\n").as_bytes())
+        };
+
+        assert_eq!(
+            of("X-Api-Key: k1\r\n").expect("key"),
+            Some("acme".to_string())
+        );
+        assert_eq!(of("").expect("anonymous"), None);
+        assert_eq!(of("X-Api-Key: unknown\r\n").expect("unknown key"), None);
+        assert_eq!(
+            of("X-Api-Key: k1\r\nX-Merchant-Id: acme\r\n").expect("matching"),
+            Some("acme".to_string())
+        );
+
+        of("X-Merchant-Id: acme\r\n").expect_err("no key");
+        of("X-Api-Key: k1\r\nX-Merchant-Id: other\r\n").expect_err("other merchant");
+    }
+}
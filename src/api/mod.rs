@@ -1,16 +1,27 @@
+mod admin;
+pub mod export;
+pub mod http;
+pub mod merchant;
 pub mod payment;
 pub mod summary;
 
-use std::{io::IoSlice, time::Instant};
+use std::{
+    io::IoSlice,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use metrics::Unit;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::UnixStream,
+    task::JoinSet,
 };
 
-use crate::{bind_unix_socket, data, get_worker_socket, worker::WorkerRequest};
+use crate::{
+    api::summary::Scope, bind_unix_socket, env_or, get_worker_socket, shutdown::Shutdown,
+    worker::PaymentRef,
+};
 
 #[tokio::main(flavor = "current_thread")]
 pub async fn serve() -> Result<()> {
@@ -20,48 +31,103 @@ pub async fn serve() -> Result<()> {
         .map(|n| format!("/var/run/api{n}.sock"))
         .unwrap_or("./api.sock".to_string());
 
-    let listener = bind_unix_socket(&socket)?;
+    run(&socket, &get_worker_socket(), Shutdown::listen()).await
+}
+
+/// Answers HTTP on `socket` through the worker on `worker_socket` until
+/// `shutdown`, then waits for the open connections.
+pub async fn run(socket: &str, worker_socket: &str, mut shutdown: Shutdown) -> Result<()> {
+    let listener = bind_unix_socket(socket)?;
     tracing::info!("binded to unix socket on {socket}");
 
+    if !admin::enabled() {
+        tracing::info!("ADMIN_TOKEN unset or empty, admin endpoints disabled");
+    }
+
+    let mut connections = JoinSet::new();
+
     loop {
-        let (socket, _) = listener.accept().await?;
+        let (socket, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            Some(_) = connections.join_next() => continue,
+            _ = shutdown.wait() => break,
+        };
 
         let counter = metrics::counter!("http.conn");
         counter.increment(1);
 
-        tokio::spawn(async {
-            if let Err(err) = handle_http(socket).await {
+        let shutdown = shutdown.clone();
+        let worker_socket = worker_socket.to_string();
+
+        connections.spawn(async {
+            if let Err(err) = handle_http(socket, worker_socket, shutdown).await {
                 tracing::error!(?err, "http_err");
             }
         });
     }
+
+    drop(listener);
+    std::fs::remove_file(socket).ok();
+
+    let deadline = Duration::from_millis(env_or("SHUTDOWN_DEADLINE_MS", 5_000));
+
+    tracing::info!("waiting for {} open connections", connections.len());
+
+    let drained = tokio::time::timeout(deadline, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+
+    if drained.is_err() {
+        tracing::warn!("{} connections still open, aborting", connections.len());
+    }
+
+    Ok(())
 }
 
-async fn handle_http(mut client: UnixStream) -> Result<()> {
+async fn handle_http(
+    mut client: UnixStream,
+    worker_socket: String,
+    mut shutdown: Shutdown,
+) -> Result<()> {
     let mut buf = [0u8; 512];
 
-    let socket = get_worker_socket();
-    let mut worker = UnixStream::connect(socket).await?;
+    let mut worker = UnixStream::connect(worker_socket).await?;
 
     loop {
-        let n = client.read(&mut buf).await?;
+        // a shutdown only closes the connection between requests, so the
+        // one being answered always finishes
+        let n = tokio::select! {
+            n = client.read(&mut buf) => n?,
+            _ = shutdown.wait() => return Ok(()),
+        };
         let now = Instant::now();
 
         if n == 0 {
             return Ok(());
         }
 
-        match (buf[0], buf[7]) {
-            // [G]ET /payments-summary
-            (b'G', _) => {
-                let n = summary::get_summary(&mut worker, &mut buf).await?;
-                let body_len = n.to_string();
+        let n = http::read_rest(&mut client, &mut buf, n).await?;
+
+        let route = {
+            let mut headers = [httparse::EMPTY_HEADER; http::MAX_HEADERS];
+
+            match http::parse(&buf[..n], &mut headers) {
+                Ok(Some(req)) => Route::of(&req),
+                Ok(None) | Err(_) => Route::BadRequest("invalid request".to_string()),
+            }
+        };
+
+        match route {
+            Route::Summary(query) => {
+                let body = summary::get_summary(&mut worker, &mut buf, query).await?;
+                let body_len = body.len().to_string();
 
                 let res = &[
                     IoSlice::new(b"HTTP/1.1 200 OK\r\nContent-Length: "),
                     IoSlice::new(body_len.as_bytes()),
                     IoSlice::new(b"\r\n\r\n"),
-                    IoSlice::new(&buf[..n]),
+                    IoSlice::new(&body),
                 ];
 
                 _ = client.write_vectored(res).await?;
@@ -69,28 +135,238 @@ async fn handle_http(mut client: UnixStream) -> Result<()> {
                 metrics::describe_histogram!("http.get", Unit::Microseconds, "http handler time");
                 metrics::histogram!("http.get").record(now.elapsed().as_micros() as f64);
             }
-            // POST /p[a]yments
-            (b'P', b'a') => {
+            Route::SummaryStream(scope) => {
+                metrics::counter!("http.summary_stream").increment(1);
+
+                // the response only ends with the connection
+                return summary::stream(&mut client, &mut worker, &mut buf, scope, &mut shutdown)
+                    .await;
+            }
+            Route::Export(query) => {
+                export::export(&mut client, &mut worker, &mut buf, query).await?;
+
+                metrics::describe_histogram!(
+                    "http.get.export",
+                    Unit::Microseconds,
+                    "http export handler time"
+                );
+                metrics::histogram!("http.get.export").record(now.elapsed().as_micros() as f64);
+            }
+            Route::Payment {
+                body_start,
+                content_length,
+                api_key,
+                merchant,
+            } => {
+                let body = &buf[body_start..n.min(body_start + content_length)];
+
+                let payment = match payment::validate(body, content_length) {
+                    Ok(payment) => payment::Request {
+                        merchant,
+                        ..payment
+                    },
+                    Err(rejection) => {
+                        rejection.record();
+                        tracing::debug!(?rejection, "payment rejected");
+                        http::respond_error(&mut client, rejection.status, &rejection.message)
+                            .await?;
+
+                        // the rest of an oversized body is still in the socket
+                        if n < body_start + content_length {
+                            return Ok(());
+                        }
+
+                        continue;
+                    }
+                };
+
+                if payment::rate_limited() {
+                    let key = api_key.unwrap_or_default();
+
+                    let (granted, wait) =
+                        payment::rate_limit(&mut worker, key, 1, &mut buf).await?;
+
+                    if let (0, Some(wait)) = (granted, wait) {
+                        metrics::counter!("http.payment.rejected", "reason" => "rate_limited")
+                            .increment(1);
+                        http::respond_rate_limited(&mut client, wait).await?;
+                        continue;
+                    }
+                }
+
+                // acknowledged only once the worker has journaled it
+                if let Err(err) = payment::send(&mut worker, payment, &mut buf).await? {
+                    payment::respond_not_journaled(&mut client, &err).await?;
+                    continue;
+                }
+
                 send_ok(&mut client).await?;
 
                 metrics::describe_histogram!("http.post", Unit::Microseconds, "http handler time");
 
                 metrics::histogram!("http.post").record(now.elapsed().as_micros() as f64);
+            }
+            Route::Batch {
+                body_start,
+                content_length,
+                ndjson,
+                api_key,
+                merchant,
+            } => {
+                if let Err(rejection) = payment::check_batch_size(content_length) {
+                    rejection.record();
+                    http::respond_error(&mut client, rejection.status, &rejection.message).await?;
+
+                    // the body is left unread
+                    return Ok(());
+                }
 
-                handle_payment(&mut worker, &mut buf).await?
+                let body =
+                    http::read_body(&mut client, &buf, n, body_start, content_length).await?;
+                let key = api_key.unwrap_or_default();
+
+                payment::batch(
+                    &mut client,
+                    &mut worker,
+                    &mut buf,
+                    &body,
+                    ndjson,
+                    key,
+                    merchant,
+                )
+                .await?;
+
+                metrics::describe_histogram!(
+                    "http.post.batch",
+                    Unit::Microseconds,
+                    "http batch handler time"
+                );
+                metrics::histogram!("http.post.batch").record(now.elapsed().as_micros() as f64);
             }
-            // POST /p[u]rge-payments
-            (b'P', b'u') => {
-                data::send(WorkerRequest::PurgeDb, &mut buf, &mut worker).await?;
-                send_ok(&mut client).await?;
+            Route::Single(action, payment) => {
+                payment::handle(&mut client, &mut worker, &mut buf, action, payment).await?
+            }
+            Route::Admin(action) => {
+                admin::handle(&mut client, &mut worker, &mut buf, action).await?
+            }
+            Route::Unauthorized => {
+                http::respond_error(&mut client, "401 Unauthorized", "invalid admin token").await?
+            }
+            Route::NotFound => {
+                http::respond_error(&mut client, "404 Not Found", "not found").await?
+            }
+            Route::BadRequest(error) => {
+                tracing::warn!("Invalid request {:?}", std::str::from_utf8(&buf[..n]));
+                http::respond_error(&mut client, "400 Bad Request", &error).await?
+            }
+        }
+    }
+}
+
+enum Route {
+    Summary(summary::Query),
+    SummaryStream(Scope),
+    Export(export::Export),
+    Payment {
+        body_start: usize,
+        content_length: usize,
+        api_key: Option<String>,
+        merchant: Option<String>,
+    },
+    Batch {
+        body_start: usize,
+        content_length: usize,
+        ndjson: bool,
+        api_key: Option<String>,
+        merchant: Option<String>,
+    },
+    Single(payment::Action, PaymentRef),
+    Admin(admin::Action),
+    Unauthorized,
+    NotFound,
+    BadRequest(String),
+}
+
+impl Route {
+    fn of(req: &http::Request) -> Self {
+        match (req.method, req.path) {
+            ("GET", "/payments-summary") => match summary_scope(req) {
+                Ok(scope) => Route::Summary(summary::query(req, scope)),
+                Err(err) => Route::BadRequest(err.to_string()),
+            },
+            ("GET", "/payments-summary/stream") => match summary_scope(req) {
+                Ok(scope) => Route::SummaryStream(scope),
+                Err(err) => Route::BadRequest(err.to_string()),
+            },
+            ("GET", "/payments/export") => {
+                match summary_scope(req).and_then(|scope| export::parse(req, scope)) {
+                    Ok(query) => Route::Export(query),
+                    Err(err) => Route::BadRequest(err.to_string()),
+                }
             }
-            _ => {
-                tracing::warn!("Invalid request {:?}", std::str::from_utf8(&buf));
+            ("POST", "/payments") => match merchant::of(req) {
+                Ok(merchant) => Route::Payment {
+                    body_start: req.body_start,
+                    content_length: req.content_length(),
+                    api_key: req.header("X-Api-Key").map(str::to_string),
+                    merchant,
+                },
+                Err(err) => Route::BadRequest(err.to_string()),
+            },
+            ("POST", "/payments/batch") => match merchant::of(req) {
+                Ok(merchant) => Route::Batch {
+                    body_start: req.body_start,
+                    content_length: req.content_length(),
+                    ndjson: req
+                        .header("Content-Type")
+                        .is_some_and(|t| t.contains("ndjson")),
+                    api_key: req.header("X-Api-Key").map(str::to_string),
+                    merchant,
+                },
+                Err(err) => Route::BadRequest(err.to_string()),
+            },
+            (method, path) => {
+                if let Some((action, correlation_id)) = payment::Action::parse(method, path) {
+                    // admins reach any payment, merchants only their own
+                    let scope = match admin::authorized(req) {
+                        true => Scope::All,
+                        false => match merchant::of(req) {
+                            Ok(merchant) => Scope::Merchant(merchant),
+                            Err(err) => return Route::BadRequest(err.to_string()),
+                        },
+                    };
+
+                    let payment = PaymentRef {
+                        correlation_id: correlation_id.to_string(),
+                        scope,
+                    };
+
+                    return Route::Single(action, payment);
+                }
+
+                match admin::Action::parse(req) {
+                    Ok(Some(_)) if !admin::authorized(req) => Route::Unauthorized,
+                    Ok(Some(action)) => Route::Admin(action),
+                    Ok(None) => Route::NotFound,
+                    Err(err) => Route::BadRequest(err.to_string()),
+                }
             }
         }
     }
 }
 
+/// Admins see every merchant, or the one they ask for.
+fn summary_scope(req: &http::Request) -> Result<Scope> {
+    if !admin::authorized(req) {
+        return Ok(Scope::Merchant(merchant::of(req)?));
+    }
+
+    match req.param("merchant") {
+        Some(merchant) => Ok(Scope::Merchant(Some(merchant.into_owned()))),
+        None => Ok(Scope::All),
+    }
+}
+
 async fn send_ok(socket: &mut UnixStream) -> Result<()> {
     socket
         .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
@@ -98,14 +374,3 @@ async fn send_ok(socket: &mut UnixStream) -> Result<()> {
 
     Ok(())
 }
-
-async fn handle_payment(socket: &mut UnixStream, buf: &mut [u8]) -> Result<(), anyhow::Error> {
-    let start = buf
-        .iter()
-        .position(|&b| b == b'{')
-        .expect("find json start");
-
-    let end = buf.iter().rposition(|&b| b == b'}').expect("find json end");
-
-    payment::send(socket, &mut buf[start..end + 1]).await
-}
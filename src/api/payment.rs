@@ -1,21 +1,753 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::OnceLock,
+    time::Duration,
+};
+
 use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::de::IgnoredAny;
 use tokio::net::UnixStream;
 
-use crate::{data, worker::WorkerRequest};
-
-pub async fn send(socket: &mut UnixStream, buf: &mut [u8]) -> Result<()> {
-    let payment: Request = serde_json::from_slice(buf)?;
+use crate::{
+    api::http,
+    data::{self, Currency},
+    env_or,
+    worker::{
+        PaymentRef, WorkerRequest,
+        refund::RefundResult,
+        status::{CancelResult, PaymentStatus},
+    },
+};
 
+/// Hands `payment` to the worker, with the inner error when it could not
+/// journal it.
+pub async fn send(
+    socket: &mut UnixStream,
+    payment: Request,
+    buf: &mut [u8],
+) -> Result<Result<(), String>> {
     tracing::trace!(payment.correlation_id, "uds_send");
 
     let req = WorkerRequest::Payment(payment);
 
-    data::send(req, buf, socket).await
+    data::send(req, buf, socket).await?;
+
+    data::recv(socket).await
+}
+
+/// Answers a payment the worker could not journal, so it was not accepted.
+pub async fn respond_not_journaled(client: &mut UnixStream, err: &str) -> Result<()> {
+    tracing::error!(err, "payment not journaled");
+    metrics::counter!("http.payment.rejected", "reason" => "journal").increment(1);
+
+    http::respond_error(
+        client,
+        "503 Service Unavailable",
+        "payment could not be recorded",
+    )
+    .await
+}
+
+/// Whether `requestedAt` is taken once, when the payment is accepted
+/// (`TIMESTAMP_POLICY=acceptance`, the default), or on every attempt to reach
+/// a processor (`TIMESTAMP_POLICY=attempt`).
+pub fn stamp_on_acceptance() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+
+    *ENABLED.get_or_init(|| !std::env::var("TIMESTAMP_POLICY").is_ok_and(|p| p == "attempt"))
+}
+
+/// Whether the worker keeps rate limits, it reads the same variables.
+pub fn rate_limited() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+
+    *ENABLED.get_or_init(|| {
+        std::env::var("RATE_LIMIT_RPS").is_ok() || std::env::var("RATE_LIMIT_QUOTAS").is_ok()
+    })
+}
+
+/// Takes up to `wanted` tokens for `key` (empty without an `X-Api-Key`)
+/// from the worker, with the wait for the next one when short.
+pub async fn rate_limit(
+    socket: &mut UnixStream,
+    key: String,
+    wanted: u32,
+    buf: &mut [u8],
+) -> Result<(u32, Option<Duration>)> {
+    data::send(WorkerRequest::RateLimit(key, wanted), buf, socket).await?;
+
+    data::recv(socket).await
+}
+
+/// Requests about a single payment, by correlation id.
+pub enum Action {
+    /// `GET /payments/{correlationId}`
+    Status,
+    /// `POST /payments/{correlationId}/cancel`, for scheduled payments.
+    Cancel,
+    /// `POST /payments/{correlationId}/refund`
+    Refund,
+}
+
+impl Action {
+    pub fn parse<'p>(method: &str, path: &'p str) -> Option<(Self, &'p str)> {
+        let target = path.strip_prefix("/payments/")?;
+
+        match (method, target.split_once('/')) {
+            ("GET", None) => Some((Action::Status, target)),
+            ("POST", Some((id, "cancel"))) => Some((Action::Cancel, id)),
+            ("POST", Some((id, "refund"))) => Some((Action::Refund, id)),
+            _ => None,
+        }
+    }
+}
+
+pub async fn handle(
+    client: &mut UnixStream,
+    worker: &mut UnixStream,
+    buf: &mut [u8],
+    action: Action,
+    payment: PaymentRef,
+) -> Result<()> {
+    let correlation_id = payment.correlation_id.clone();
+
+    match action {
+        Action::Status => {
+            data::send(WorkerRequest::Status(payment), buf, worker).await?;
+            status(client, &correlation_id, data::recv(worker).await?).await
+        }
+        Action::Cancel => {
+            data::send(WorkerRequest::Cancel(payment), buf, worker).await?;
+
+            match data::recv(worker).await? {
+                CancelResult::Cancelled => http::respond(client, "200 OK", b"").await,
+                CancelResult::NotScheduled => {
+                    http::respond_error(client, "409 Conflict", "payment is not scheduled").await
+                }
+                CancelResult::NotFound => {
+                    http::respond_error(client, "404 Not Found", "payment not found").await
+                }
+            }
+        }
+        Action::Refund => {
+            data::send(WorkerRequest::Refund(payment), buf, worker).await?;
+            refund(client, &correlation_id, data::recv(worker).await?).await
+        }
+    }
+}
+
+async fn status(
+    client: &mut UnixStream,
+    correlation_id: &str,
+    status: PaymentStatus,
+) -> Result<()> {
+    let date = |micros| DateTime::from_timestamp_micros(micros).map(|d| d.to_rfc3339());
+
+    let body = match status {
+        PaymentStatus::Scheduled {
+            scheduled_at,
+            amount,
+            currency,
+        } => serde_json::json!({
+            "correlationId": correlation_id,
+            "status": "scheduled",
+            "scheduledAt": date(scheduled_at),
+            "amount": data::cents::to_decimal(amount),
+            "currency": currency,
+        }),
+        PaymentStatus::Pending => serde_json::json!({
+            "correlationId": correlation_id,
+            "status": "pending",
+        }),
+        PaymentStatus::Processed {
+            processor,
+            amount,
+            currency,
+            requested_at,
+            refunded,
+        } => serde_json::json!({
+            "correlationId": correlation_id,
+            "status": if refunded { "refunded" } else { "processed" },
+            "processor": processor,
+            "amount": data::cents::to_decimal(amount),
+            "currency": currency,
+            "requestedAt": date(requested_at),
+        }),
+        PaymentStatus::NotFound => {
+            return http::respond_error(client, "404 Not Found", "payment not found").await;
+        }
+    };
+
+    http::respond(client, "200 OK", body.to_string().as_bytes()).await
+}
+
+async fn refund(client: &mut UnixStream, correlation_id: &str, result: RefundResult) -> Result<()> {
+    match result {
+        RefundResult::Refunded {
+            processor,
+            amount,
+            currency,
+        } => {
+            let body = serde_json::json!({
+                "correlationId": correlation_id,
+                "processor": processor,
+                "amount": data::cents::to_decimal(amount),
+                "currency": currency,
+            });
+
+            http::respond(client, "200 OK", body.to_string().as_bytes()).await
+        }
+        RefundResult::NotFound => {
+            http::respond_error(client, "404 Not Found", "payment not found").await
+        }
+        RefundResult::AlreadyRefunded => {
+            http::respond_error(client, "409 Conflict", "payment already refunded").await
+        }
+        RefundResult::Failed(err) => {
+            let error = format!("processor refused the refund: {err}");
+            http::respond_error(client, "502 Bad Gateway", &error).await
+        }
+    }
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Request {
     pub correlation_id: String,
-    pub amount: f32,
+    /// Cents.
+    pub amount: u64,
+    pub currency: Currency,
+    /// Taken from the request headers, never from the body.
+    pub merchant: Option<String>,
+    /// Micros, the payment waits in the worker until then.
+    pub scheduled_at: Option<i64>,
+    /// Webhook for this payment, over the merchant's one.
+    pub callback_url: Option<String>,
+    /// Failed deliveries so far, kept by the worker.
+    pub attempts: u32,
+    /// Micros, the `requestedAt` sent on every attempt and stored. Taken when
+    /// the API accepts the payment, `None` stamps each attempt instead.
+    pub requested_at: Option<i64>,
+    /// Processor every retry goes to, once an attempt there may have gone
+    /// through. Kept by the worker only.
+    #[serde(skip)]
+    pub processor: Option<u8>,
+}
+
+const MAX_CALLBACK_URL_LEN: usize = 256;
+
+/// Largest accepted `amount`, keeps the summed cents far from overflowing.
+pub const MAX_AMOUNT: f64 = 1_000_000_000.0;
+
+/// The body of a `POST /payments`.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Body {
+    correlation_id: String,
+    amount: f64,
+    currency: Option<String>,
+    scheduled_at: Option<String>,
+    callback_url: Option<String>,
+    /// Refused with `UNKNOWN_FIELDS=reject`.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, IgnoredAny>,
+}
+
+struct Validation {
+    max_body: usize,
+    reject_unknown_fields: bool,
+    max_batch_body: usize,
+    max_batch_items: usize,
+}
+
+fn validation() -> &'static Validation {
+    static VALIDATION: OnceLock<Validation> = OnceLock::new();
+
+    VALIDATION.get_or_init(|| Validation {
+        max_body: env_or("MAX_BODY_BYTES", 256),
+        reject_unknown_fields: std::env::var("UNKNOWN_FIELDS").is_ok_and(|p| p == "reject"),
+        max_batch_body: env_or("MAX_BATCH_BYTES", 1 << 20),
+        max_batch_items: env_or("MAX_BATCH_ITEMS", 1_000),
+    })
+}
+
+#[derive(Debug)]
+pub struct Rejection {
+    pub status: &'static str,
+    /// Short label for the rejection metric.
+    pub reason: &'static str,
+    pub message: String,
+}
+
+impl Rejection {
+    fn bad_request(reason: &'static str, message: impl ToString) -> Self {
+        Self {
+            status: "400 Bad Request",
+            reason,
+            message: message.to_string(),
+        }
+    }
+
+    fn rate_limited() -> Self {
+        Self {
+            status: "429 Too Many Requests",
+            reason: "rate_limited",
+            message: "rate limit exceeded".to_string(),
+        }
+    }
+
+    fn too_large(message: impl ToString) -> Self {
+        Self {
+            status: "413 Payload Too Large",
+            reason: "batch_too_large",
+            message: message.to_string(),
+        }
+    }
+
+    fn unprocessable(reason: &'static str, message: impl ToString) -> Self {
+        Self {
+            status: "422 Unprocessable Entity",
+            reason,
+            message: message.to_string(),
+        }
+    }
+
+    fn parse(err: serde_json::Error) -> Self {
+        match err.classify() {
+            serde_json::error::Category::Data => Self::unprocessable("invalid_field", err),
+            _ => Self::bad_request("malformed_json", err),
+        }
+    }
+
+    pub fn record(&self) {
+        metrics::counter!("http.payment.rejected", "reason" => self.reason).increment(1);
+    }
+}
+
+/// Checks a `POST /payments` body before it is acknowledged. `content_length`
+/// is the declared size, which may exceed what fit in `body`.
+pub fn validate(body: &[u8], content_length: usize) -> Result<Request, Rejection> {
+    let validation = validation();
+
+    if content_length > validation.max_body || body.len() < content_length {
+        return Err(Rejection::bad_request(
+            "body_too_large",
+            format!("body must have at most {} bytes", validation.max_body),
+        ));
+    }
+
+    check(serde_json::from_slice(body).map_err(Rejection::parse)?)
+}
+
+/// The checks of [`validate`] on an already parsed body.
+fn check(body: Body) -> Result<Request, Rejection> {
+    let validation = validation();
+
+    let Body {
+        correlation_id,
+        amount,
+        currency,
+        scheduled_at,
+        callback_url,
+        unknown,
+    } = body;
+
+    if let Some(field) = unknown
+        .keys()
+        .next()
+        .filter(|_| validation.reject_unknown_fields)
+    {
+        return Err(Rejection::unprocessable(
+            "invalid_field",
+            format!("unknown field `{field}`"),
+        ));
+    }
+
+    if data::uuid_to_u128(&correlation_id).is_none() {
+        return Err(Rejection::unprocessable(
+            "invalid_correlation_id",
+            "correlationId must be a UUID",
+        ));
+    }
+
+    if !amount.is_finite() || amount <= 0.0 {
+        return Err(Rejection::unprocessable(
+            "invalid_amount",
+            "amount must be positive",
+        ));
+    }
+
+    if amount > MAX_AMOUNT {
+        return Err(Rejection::unprocessable(
+            "invalid_amount",
+            format!("amount must be at most {MAX_AMOUNT}"),
+        ));
+    }
+
+    let cents = amount * 100.0;
+    if (cents - cents.round()).abs() > 1e-6 {
+        return Err(Rejection::unprocessable(
+            "invalid_amount",
+            "amount must have at most two decimals",
+        ));
+    }
+
+    let currency = match currency {
+        None => Currency::default(),
+        Some(code) => code
+            .parse()
+            .ok()
+            .filter(|currency| Currency::allowed().contains(currency))
+            .ok_or_else(|| {
+                let allowed = Currency::allowed().iter().map(Currency::as_str);
+
+                Rejection::unprocessable(
+                    "invalid_currency",
+                    format!(
+                        "currency must be one of {}",
+                        allowed.collect::<Vec<_>>().join(", ")
+                    ),
+                )
+            })?,
+    };
+
+    let scheduled_at = match scheduled_at {
+        None => None,
+        Some(date) => {
+            let date = DateTime::parse_from_rfc3339(&date).map_err(|_| {
+                Rejection::unprocessable(
+                    "invalid_scheduled_at",
+                    "scheduledAt must be an RFC 3339 date",
+                )
+            })?;
+
+            Some(date.timestamp_micros())
+        }
+    };
+
+    let is_url = |url: &str| url.starts_with("http://") || url.starts_with("https://");
+
+    if callback_url.as_deref().is_some_and(|url| !is_url(url)) {
+        return Err(Rejection::unprocessable(
+            "invalid_callback_url",
+            "callbackUrl must be an http(s) URL",
+        ));
+    }
+
+    // the payment is framed to the worker in the connection's buffer
+    if callback_url
+        .as_ref()
+        .is_some_and(|url| url.len() > MAX_CALLBACK_URL_LEN)
+    {
+        return Err(Rejection::unprocessable(
+            "invalid_callback_url",
+            format!("callbackUrl must have at most {MAX_CALLBACK_URL_LEN} bytes"),
+        ));
+    }
+
+    // a scheduled payment is requested when it is due
+    let requested_at = stamp_on_acceptance().then(|| {
+        let now = Utc::now().timestamp_micros();
+        scheduled_at.map_or(now, |at| at.max(now))
+    });
+
+    Ok(Request {
+        correlation_id,
+        amount: cents.round() as u64,
+        currency,
+        merchant: None,
+        scheduled_at,
+        callback_url,
+        attempts: 0,
+        requested_at,
+        processor: None,
+    })
+}
+
+/// Whether a `POST /payments/batch` body of `content_length` bytes may be
+/// read at all.
+pub fn check_batch_size(content_length: usize) -> Result<(), Rejection> {
+    let max = validation().max_batch_body;
+
+    match content_length > max {
+        true => Err(Rejection::too_large(format!(
+            "batch must have at most {max} bytes"
+        ))),
+        false => Ok(()),
+    }
+}
+
+/// Splits a `POST /payments/batch` body, a JSON array or NDJSON, validating
+/// each payment on its own.
+pub fn validate_batch(
+    body: &[u8],
+    ndjson: bool,
+) -> Result<Vec<Result<Request, Rejection>>, Rejection> {
+    let max = validation().max_batch_items;
+    let too_large = || Rejection::too_large(format!("batch must have at most {max} payments"));
+
+    let items: Vec<Result<Request, Rejection>> = match ndjson {
+        true => {
+            let lines: Vec<&[u8]> = body
+                .split(|b| *b == b'\n')
+                .filter(|line| !line.trim_ascii().is_empty())
+                .collect();
+
+            if lines.len() > max {
+                return Err(too_large());
+            }
+
+            lines
+                .into_iter()
+                .map(|line| validate(line, line.len()))
+                .collect()
+        }
+        false => {
+            let values = serde_json::from_slice::<Vec<serde_json::Value>>(body)
+                .map_err(|err| Rejection::bad_request("malformed_json", err))?;
+
+            if values.len() > max {
+                return Err(too_large());
+            }
+
+            values
+                .into_iter()
+                .map(|value| check(serde_json::from_value(value).map_err(Rejection::parse)?))
+                .collect()
+        }
+    };
+
+    let mut seen = HashSet::new();
+
+    let results = items
+        .into_iter()
+        .map(|item| {
+            let req = item?;
+
+            match seen.insert(req.correlation_id.clone()) {
+                true => Ok(req),
+                false => Err(Rejection::unprocessable(
+                    "duplicate_correlation_id",
+                    "correlationId repeated in the batch",
+                )),
+            }
+        })
+        .collect();
+
+    Ok(results)
+}
+
+/// Validates a batch and forwards its accepted payments to the worker in a
+/// single frame, answering with the outcome of each item in order.
+pub async fn batch(
+    client: &mut UnixStream,
+    worker: &mut UnixStream,
+    buf: &mut [u8],
+    body: &[u8],
+    ndjson: bool,
+    key: String,
+    merchant: Option<String>,
+) -> Result<()> {
+    let mut results = match validate_batch(body, ndjson) {
+        Ok(results) => results,
+        Err(rejection) => {
+            rejection.record();
+            return http::respond_error(client, rejection.status, &rejection.message).await;
+        }
+    };
+
+    let valid = results.iter().filter(|r| r.is_ok()).count() as u32;
+
+    if rate_limited() && valid > 0 {
+        let (granted, _) = rate_limit(worker, key, valid, buf).await?;
+
+        // items past the granted tokens are refused, the first ones go through
+        for result in results
+            .iter_mut()
+            .filter(|r| r.is_ok())
+            .skip(granted as usize)
+        {
+            *result = Err(Rejection::rate_limited());
+        }
+    }
+
+    let items: Vec<_> = results
+        .iter()
+        .enumerate()
+        .map(|(index, result)| match result {
+            Ok(req) => serde_json::json!({
+                "index": index,
+                "correlationId": req.correlation_id,
+                "status": "accepted",
+            }),
+            Err(rejection) => {
+                rejection.record();
+                serde_json::json!({
+                    "index": index,
+                    "status": "rejected",
+                    "error": rejection.message,
+                })
+            }
+        })
+        .collect();
+
+    let payments: Vec<_> = results
+        .into_iter()
+        .filter_map(Result::ok)
+        .map(|req| Request {
+            merchant: merchant.clone(),
+            ..req
+        })
+        .collect();
+
+    let accepted = payments.len();
+
+    if accepted > 0 {
+        tracing::trace!(accepted, "uds_send batch");
+        data::send_large(WorkerRequest::Batch(payments), worker).await?;
+
+        if let Err(err) = data::recv::<Result<(), String>, _>(worker).await? {
+            return respond_not_journaled(client, &err).await;
+        }
+    }
+
+    let body = serde_json::json!({
+        "accepted": accepted,
+        "rejected": items.len() - accepted,
+        "items": items,
+    });
+
+    http::respond(client, "200 OK", body.to_string().as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reason(body: &str) -> &'static str {
+        validate(body.as_bytes(), body.len())
+            .expect_err("rejected")
+            .reason
+    }
+
+    #[test]
+    fn test_validate() {
+        let body = r#"{"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","amount":19.9}"#;
+
+        let req = validate(body.as_bytes(), body.len()).expect("valid");
+        assert_eq!(req.correlation_id, "4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3");
+        assert_eq!(req.amount, 1990);
+
+        assert_eq!(reason(r#"{"correlationId":"4a79"#), "malformed_json");
+        assert_eq!(reason(r#"{"amount":19.9}"#), "invalid_field");
+        assert_eq!(
+            reason(r#"{"correlationId":"not-a-uuid","amount":19.9}"#),
+            "invalid_correlation_id"
+        );
+        assert_eq!(
+            reason(r#"{"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","amount":-1}"#),
+            "invalid_amount"
+        );
+        assert_eq!(
+            reason(r#"{"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","amount":1.999}"#),
+            "invalid_amount"
+        );
+        assert_eq!(
+            reason(r#"{"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","amount":1e20}"#),
+            "invalid_amount"
+        );
+
+        assert_eq!(req.currency.as_str(), "BRL");
+
+        let with_currency = r#"{"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","amount":1,"currency":"BRL"}"#;
+
+        let req = validate(with_currency.as_bytes(), with_currency.len()).expect("allowed");
+        assert_eq!(req.currency, Currency::default());
+
+        assert_eq!(
+            reason(
+                r#"{"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","amount":1,"currency":"XYZ"}"#
+            ),
+            "invalid_currency"
+        );
+        assert_eq!(
+            reason(
+                r#"{"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","amount":1,"currency":"brl"}"#
+            ),
+            "invalid_currency"
+        );
+
+        let scheduled = r#"{"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","amount":1,"scheduledAt":"2030-01-01T00:00:00Z"}"#;
+
+        let req = validate(scheduled.as_bytes(), scheduled.len()).expect("scheduled");
+        assert_eq!(req.scheduled_at, Some(1_893_456_000_000_000));
+        // stamped when due
+        assert_eq!(req.requested_at, Some(1_893_456_000_000_000));
+
+        assert_eq!(
+            reason(
+                r#"{"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","amount":1,"scheduledAt":"tomorrow"}"#
+            ),
+            "invalid_scheduled_at"
+        );
+
+        assert_eq!(
+            reason(
+                r#"{"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","amount":1,"callbackUrl":"ftp://x"}"#
+            ),
+            "invalid_callback_url"
+        );
+
+        let rejection = validate(body.as_bytes(), 4096).expect_err("too large");
+        assert_eq!(rejection.reason, "body_too_large");
+        assert_eq!(rejection.status, "400 Bad Request");
+    }
+
+    #[test]
+    fn test_validate_batch() {
+        let id = |i| format!("00000000-0000-4000-8000-{i:012}");
+
+        let array = format!(
+            r#"[{{"correlationId":"{}","amount":1}},{{"correlationId":"{}","amount":-1}},{{"correlationId":"{}","amount":2}}]"#,
+            id(1),
+            id(2),
+            id(1)
+        );
+
+        let results = validate_batch(array.as_bytes(), false).expect("valid batch");
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().expect("accepted").correlation_id, id(1));
+        assert_eq!(
+            results[1].as_ref().expect_err("rejected").reason,
+            "invalid_amount"
+        );
+        assert_eq!(
+            results[2].as_ref().expect_err("rejected").reason,
+            "duplicate_correlation_id"
+        );
+
+        let ndjson = format!(
+            "{{\"correlationId\":\"{}\",\"amount\":1}}\n\n{{\"correlationId\":\"{}\"\n",
+            id(1),
+            id(2)
+        );
+
+        let results = validate_batch(ndjson.as_bytes(), true).expect("valid batch");
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert_eq!(
+            results[1].as_ref().expect_err("rejected").reason,
+            "malformed_json"
+        );
+
+        assert_eq!(
+            validate_batch(b"[{", false).expect_err("malformed").reason,
+            "malformed_json"
+        );
+        assert_eq!(
+            check_batch_size(10 << 20).expect_err("too large").status,
+            "413 Payload Too Large"
+        );
+    }
 }
@@ -1,71 +1,220 @@
 use anyhow::Result;
 use chrono::DateTime;
-use tokio::{io::AsyncReadExt, net::UnixStream};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
 
-use crate::{data, worker::WorkerRequest};
-
-pub async fn get_summary(socket: &mut UnixStream, buf: &mut [u8]) -> Result<usize> {
-    let query = get_query(buf)?;
+use crate::{
+    api::http,
+    data::{self, Currency},
+    db::CurrencyTotals,
+    shutdown::Shutdown,
+    worker::{WorkerRequest, summary::build_payload},
+};
 
+/// The summary JSON body, built by the worker.
+pub async fn get_summary(socket: &mut UnixStream, buf: &mut [u8], query: Query) -> Result<Vec<u8>> {
     let req = WorkerRequest::Summary(query);
 
     data::send(req, buf, socket).await?;
 
-    let n = socket.read(buf).await?;
+    data::recv(socket).await
+}
+
+/// Pushes an SSE `summary` event with every processor's totals each time the
+/// worker reports a change, until either side closes or the API shuts down.
+pub async fn stream(
+    client: &mut UnixStream,
+    worker: &mut UnixStream,
+    buf: &mut [u8],
+    scope: Scope,
+    shutdown: &mut Shutdown,
+) -> Result<()> {
+    data::send(WorkerRequest::SummaryStream(scope), buf, worker).await?;
+
+    client
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n",
+        )
+        .await?;
+
+    let mut worker = data::FramedStream::new(worker);
+    let mut processors = Vec::new();
+    let mut totals = CurrencyTotals::new();
+
+    loop {
+        tokio::select! {
+            n = worker.read() => {
+                if n? == 0 {
+                    return Ok(());
+                }
+            }
+            // anything sent by the client is ignored, only the close matters
+            n = client.read(buf) => {
+                if n? == 0 {
+                    return Ok(());
+                }
+                continue;
+            }
+            _ = shutdown.wait() => return Ok(()),
+        }
+
+        while let Some(update) = worker.next()? {
+            match update {
+                Update::Snapshot {
+                    processors: names,
+                    totals: snapshot,
+                } => {
+                    processors = names;
+                    totals = snapshot;
+                }
+                Update::Delta(delta) => apply(&mut totals, delta),
+            }
+
+            let summaries: Vec<_> = totals
+                .iter()
+                .map(|(currency, totals)| Summary::new(&processors, *currency, totals.clone()))
+                .collect();
 
-    Ok(n)
+            let mut event = b"event: summary\ndata: ".to_vec();
+            build_payload(&mut event, &summaries)?;
+            event.extend_from_slice(b"\n\n");
+
+            client.write_all(&event).await?;
+        }
+    }
 }
 
-fn get_query(buf: &[u8]) -> Result<(i64, i64)> {
-    const DISTANT_FUTURE: DateTime<chrono::Utc> = DateTime::from_timestamp_nanos(i64::MAX);
+fn apply(totals: &mut CurrencyTotals, delta: CurrencyTotals) {
+    for (currency, delta) in delta {
+        match totals.iter_mut().find(|(c, _)| *c == currency) {
+            Some((_, totals)) => {
+                for (acc, delta) in totals.iter_mut().zip(delta) {
+                    *acc += delta;
+                }
+            }
+            None => totals.push((currency, delta)),
+        }
+    }
+}
+
+/// Sent by the worker on a summary stream: the whole totals first, then only
+/// what changed since the previous update.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Update {
+    Snapshot {
+        processors: Vec<String>,
+        totals: CurrencyTotals,
+    },
+    Delta(CurrencyTotals),
+}
 
-    const RFC_3339_SIZE: usize = "2001-04-27T15:30:05.000Z".len();
-    const QUERY_STRING_OFFSET: usize = "GET /payments-summary".len();
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Query {
+    /// Inclusive `requested_at` range in micros.
+    pub range: (i64, i64),
+    pub scope: Scope,
+}
 
-    const FROM_FIELD_SIZE: usize = "?from=".len();
-    const FROM_START: usize = QUERY_STRING_OFFSET + FROM_FIELD_SIZE;
-    const FROM_END: usize = FROM_START + RFC_3339_SIZE;
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Scope {
+    /// Payments of a single merchant, `None` for those sent without one.
+    Merchant(Option<String>),
+    /// Every merchant together.
+    All,
+}
 
-    const TO_FIELD_SIZE: usize = "&to=".len();
-    const TO_START: usize = FROM_END + TO_FIELD_SIZE;
-    const TO_END: usize = TO_START + RFC_3339_SIZE;
+impl Scope {
+    pub fn allows(&self, merchant: Option<&str>) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::Merchant(own) => own.as_deref() == merchant,
+        }
+    }
+}
 
-    let from = &buf[FROM_START..FROM_END];
-    let to = &buf[TO_START..TO_END];
+/// Reads `from`/`to` (RFC 3339), each open ended when missing or invalid.
+pub fn query(req: &http::Request, scope: Scope) -> Query {
+    const DISTANT_FUTURE: DateTime<chrono::Utc> = DateTime::from_timestamp_nanos(i64::MAX);
 
-    let from = std::str::from_utf8(from)?;
-    let to = std::str::from_utf8(to)?;
+    let date = |name| {
+        req.param(name)
+            .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+    };
 
-    let from = DateTime::parse_from_rfc3339(from).unwrap_or_default();
-    let to = DateTime::parse_from_rfc3339(to).unwrap_or(DISTANT_FUTURE.into());
+    let from = date("from").unwrap_or_default();
+    let to = date("to").unwrap_or(DISTANT_FUTURE.into());
 
-    Ok((from.timestamp_micros(), to.timestamp_micros()))
+    Query {
+        range: (from.timestamp_micros(), to.timestamp_micros()),
+        scope,
+    }
 }
 
 pub struct Summary {
-    pub default: ProcessedData,
-    pub fallback: ProcessedData,
+    pub currency: Currency,
+    pub processors: Vec<(String, ProcessedData)>,
 }
 
 impl Summary {
-    pub fn new(summary: [(u64, u64); 2]) -> Self {
+    pub fn new(names: &[String], currency: Currency, summary: Vec<Totals>) -> Self {
+        let processors = names
+            .iter()
+            .cloned()
+            .zip(summary.into_iter().map(ProcessedData::new))
+            .collect();
+
         Summary {
-            default: ProcessedData::new(summary[0]),
-            fallback: ProcessedData::new(summary[1]),
+            currency,
+            processors,
         }
     }
+
+    pub fn get(&self, name: &str) -> Option<&ProcessedData> {
+        self.processors
+            .iter()
+            .find_map(|(n, data)| (n == name).then_some(data))
+    }
+}
+
+/// Ledger totals of a processor, amounts in cents.
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Totals {
+    pub count: u64,
+    pub amount: u64,
+    pub refunds: u64,
+    pub refunded: u64,
+}
+
+impl std::ops::AddAssign for Totals {
+    fn add_assign(&mut self, other: Self) {
+        self.count += other.count;
+        self.amount += other.amount;
+        self.refunds += other.refunds;
+        self.refunded += other.refunded;
+    }
 }
 
 pub struct ProcessedData {
     pub count: u64,
+    /// Gross amount, refunds are not taken out.
     pub amount: f32,
+    pub refunds: u64,
+    pub refunded: f32,
+    pub net: f32,
 }
 
 impl ProcessedData {
-    pub fn new((requests, amount): (u64, u64)) -> Self {
+    pub fn new(totals: Totals) -> Self {
+        let cents = |cents: i64| cents as f32 / 100.0;
+
         ProcessedData {
-            count: requests,
-            amount: amount as f32 / 100.0,
+            count: totals.count,
+            amount: cents(totals.amount as i64),
+            refunds: totals.refunds,
+            refunded: cents(totals.refunded as i64),
+            net: cents(totals.amount as i64 - totals.refunded as i64),
         }
     }
 }
@@ -77,8 +226,12 @@ mod tests {
 
     #[test]
     fn test_add() {
-        let input =
-            b"GET /payments-summary?from=2001-04-27T12:30:00.000Z&to=2025-05-27T15:37:50.000Z";
+        let input = b"GET /payments-summary?from=2001-04-27T12:30:00.000Z&to=2025-05-27T15:37:50.000Z HTTP/1.1\r\n\r\n";
+
+        let mut headers = [httparse::EMPTY_HEADER; http::MAX_HEADERS];
+        let req = http::parse(input, &mut headers)
+            .expect("parse")
+            .expect("complete");
 
         let date = NaiveDate::from_ymd_opt(2001, 4, 27).expect("valid date");
         let time = NaiveTime::from_hms_opt(12, 30, 0).expect("valid time");
@@ -92,8 +245,12 @@ mod tests {
 
         let to: DateTime<Utc> = DateTime::from_naive_utc_and_offset(datetime, Utc);
 
-        let result = get_query(input).expect("get query");
+        let result = query(&req, Scope::All);
 
-        assert_eq!(result, (from.timestamp_micros(), to.timestamp_micros()));
+        assert_eq!(
+            result.range,
+            (from.timestamp_micros(), to.timestamp_micros())
+        );
+        assert_eq!(result.scope, Scope::All);
     }
 }
@@ -0,0 +1,432 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use chrono::{SecondsFormat, Utc};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+    task::JoinSet,
+    time::Instant,
+};
+
+use crate::{data, env_or};
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// `unix:<path>` for an API socket, otherwise a TCP `host:port` (nginx).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Target {
+    pub fn parse(target: &str) -> Self {
+        match target.strip_prefix("unix:") {
+            Some(path) => Target::Unix(path.into()),
+            None => Target::Tcp(target.to_string()),
+        }
+    }
+
+    pub async fn connect(&self) -> Result<Conn> {
+        let stream: Box<dyn Stream> = match self {
+            Target::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).await?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+            Target::Unix(path) => Box::new(UnixStream::connect(path).await?),
+        };
+
+        Ok(Conn {
+            stream,
+            buf: vec![0u8; 4096],
+        })
+    }
+}
+
+/// Keep-alive HTTP/1.1 connection, one request at a time.
+pub struct Conn {
+    stream: Box<dyn Stream>,
+    buf: Vec<u8>,
+}
+
+impl Conn {
+    /// The status and body of the response.
+    pub async fn request(
+        &mut self,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Result<(u16, Vec<u8>)> {
+        let head = format!(
+            "{method} {path} HTTP/1.1\r\nHost: bench\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+
+        self.stream.write_all(head.as_bytes()).await?;
+        self.stream.write_all(body).await?;
+
+        let mut n = 0;
+
+        let (status, body_start, content_length) = loop {
+            if n == self.buf.len() {
+                self.buf.resize(self.buf.len() * 2, 0);
+            }
+
+            let read = self.stream.read(&mut self.buf[n..]).await?;
+            if read == 0 {
+                return Err(anyhow!("connection closed"));
+            }
+            n += read;
+
+            let mut headers = [httparse::EMPTY_HEADER; 16];
+            let mut res = httparse::Response::new(&mut headers);
+
+            if let httparse::Status::Complete(body_start) = res.parse(&self.buf[..n])? {
+                let content_length = res
+                    .headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|h| std::str::from_utf8(h.value).ok()?.trim().parse().ok())
+                    .unwrap_or(0);
+
+                break (res.code.unwrap_or_default(), body_start, content_length);
+            }
+        };
+
+        let mut body = self.buf[body_start..n].to_vec();
+        let read = body.len();
+        body.resize(content_length, 0);
+
+        if content_length > read {
+            self.stream.read_exact(&mut body[read..]).await?;
+        }
+
+        Ok((status, body))
+    }
+}
+
+struct Config {
+    target: Target,
+    /// Requests per second over every connection, unlimited on `0`.
+    rate: f64,
+    concurrency: usize,
+    /// A summary query after every that many payments, none on `0`.
+    summary_every: usize,
+    /// How long the summary may take to catch up with the accepted payments.
+    settle: Duration,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let target = std::env::var("BENCH_TARGET").unwrap_or("unix:./api.sock".to_string());
+
+        Self {
+            target: Target::parse(&target),
+            rate: env_or("BENCH_RATE", 0.0),
+            concurrency: env_or("BENCH_CONCURRENCY", 16).max(1),
+            summary_every: env_or("BENCH_SUMMARY_EVERY", 100),
+            settle: Duration::from_millis(env_or("BENCH_SETTLE_MS", 10_000)),
+        }
+    }
+}
+
+/// A `POST /payments` body and its amount in cents.
+struct Payment {
+    body: String,
+    cents: u64,
+}
+
+#[derive(Default)]
+struct Results {
+    payments: Vec<u64>,
+    summaries: Vec<u64>,
+    /// Cents of every payment answered with `200`.
+    accepted: Vec<u64>,
+    failed: usize,
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        self.payments.extend(other.payments);
+        self.summaries.extend(other.summaries);
+        self.accepted.extend(other.accepted);
+        self.failed += other.failed;
+    }
+}
+
+/// Sends the payments in `file` (one JSON body per line) or `BENCH_REQUESTS`
+/// synthetic ones to the API, then prints throughput, latencies and whether
+/// the summary matches what was accepted.
+#[tokio::main(flavor = "current_thread")]
+pub async fn serve(file: Option<&Path>) -> Result<()> {
+    let config = Arc::new(Config::from_env());
+
+    let payments = match file {
+        Some(file) => read_payments(&std::fs::read_to_string(file)?)?,
+        None => synthetic(
+            env_or("BENCH_REQUESTS", 10_000),
+            env_or("BENCH_AMOUNT", 1990),
+        ),
+    };
+    let payments = Arc::new(payments);
+
+    tracing::info!(
+        "sending {} payments to {:?} over {} connections",
+        payments.len(),
+        config.target,
+        config.concurrency
+    );
+
+    let from = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+    let start = Instant::now();
+    let next = Arc::new(AtomicUsize::new(0));
+
+    let mut tasks = JoinSet::new();
+
+    for _ in 0..config.concurrency {
+        let config = config.clone();
+        let payments = payments.clone();
+        let next = next.clone();
+
+        tasks.spawn(async move { run(&config, &payments, &next, start).await });
+    }
+
+    let mut results = Results::default();
+    while let Some(result) = tasks.join_next().await {
+        results.merge(result??);
+    }
+
+    let elapsed = start.elapsed();
+
+    // the worker may still be sending queued payments to the processors
+    let mut conn = config.target.connect().await?;
+    let settled = Instant::now();
+
+    let summary = loop {
+        let path = format!("/payments-summary?from={from}");
+        let (_, summary) = conn.request("GET", &path, b"").await?;
+
+        let (count, _) = summary_totals(&serde_json::from_slice(&summary)?);
+
+        if count >= results.accepted.len() as u64 || settled.elapsed() >= config.settle {
+            break summary;
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+
+    let report = report(results, elapsed, &summary)?;
+    println!("{report}");
+
+    Ok(())
+}
+
+async fn run(
+    config: &Config,
+    payments: &[Payment],
+    next: &AtomicUsize,
+    start: Instant,
+) -> Result<Results> {
+    let mut results = Results::default();
+    let mut conn = config.target.connect().await?;
+
+    loop {
+        let i = next.fetch_add(1, Ordering::Relaxed);
+
+        let Some(payment) = payments.get(i) else {
+            return Ok(results);
+        };
+
+        if config.rate > 0.0 {
+            let at = start + Duration::from_secs_f64(i as f64 / config.rate);
+            tokio::time::sleep_until(at).await;
+        }
+
+        let now = Instant::now();
+
+        match conn
+            .request("POST", "/payments", payment.body.as_bytes())
+            .await
+        {
+            Ok((200, _)) => results.accepted.push(payment.cents),
+            Ok(_) => results.failed += 1,
+            Err(err) => {
+                tracing::debug!(?err, "payment failed, reconnecting");
+                results.failed += 1;
+                conn = config.target.connect().await?;
+                continue;
+            }
+        }
+
+        results.payments.push(now.elapsed().as_micros() as u64);
+
+        if config.summary_every > 0 && (i + 1).is_multiple_of(config.summary_every) {
+            let now = Instant::now();
+
+            if conn.request("GET", "/payments-summary", b"").await.is_ok() {
+                results.summaries.push(now.elapsed().as_micros() as u64);
+            }
+        }
+    }
+}
+
+fn read_payments(input: &str) -> Result<Vec<Payment>> {
+    input
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let body: serde_json::Value = serde_json::from_str(line)?;
+            let amount = body["amount"].as_f64().unwrap_or_default();
+
+            Ok(Payment {
+                body: line.to_string(),
+                cents: (amount * 100.0).round() as u64,
+            })
+        })
+        .collect()
+}
+
+fn synthetic(count: usize, cents: u64) -> Vec<Payment> {
+    // xorshift, seeded by the clock so runs do not collide
+    let mut state = Utc::now().timestamp_nanos_opt().unwrap_or(1) as u128 | 1;
+
+    (0..count)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            // version 4, variant 1
+            let id = (state & !(0xf << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62);
+
+            Payment {
+                body: format!(
+                    r#"{{"correlationId":"{}","amount":{}.{:02}}}"#,
+                    data::u128_to_uuid(id),
+                    cents / 100,
+                    cents % 100
+                ),
+                cents,
+            }
+        })
+        .collect()
+}
+
+fn latencies(samples: &mut [u64]) -> serde_json::Value {
+    samples.sort_unstable();
+
+    let percentile = |p: f64| match samples.len() {
+        0 => 0,
+        len => samples[((len - 1) as f64 * p).round() as usize],
+    };
+
+    serde_json::json!({
+        "count": samples.len(),
+        "p50": percentile(0.50),
+        "p99": percentile(0.99),
+        "max": samples.last().copied().unwrap_or_default(),
+    })
+}
+
+/// Adds up `totalRequests` and `totalAmount` of every processor, in any
+/// currency, of a summary body.
+fn summary_totals(summary: &serde_json::Value) -> (u64, f64) {
+    match summary.as_object() {
+        Some(fields) if fields.contains_key("totalRequests") => (
+            summary["totalRequests"].as_u64().unwrap_or_default(),
+            summary["totalAmount"].as_f64().unwrap_or_default(),
+        ),
+        Some(fields) => fields
+            .values()
+            .map(summary_totals)
+            .fold((0, 0.0), |(c, a), (count, amount)| (c + count, a + amount)),
+        None => (0, 0.0),
+    }
+}
+
+fn report(mut results: Results, elapsed: Duration, summary: &[u8]) -> Result<serde_json::Value> {
+    let expected_count = results.accepted.len() as u64;
+    let expected_amount = results.accepted.iter().sum::<u64>() as f64 / 100.0;
+
+    let (count, amount) = summary_totals(&serde_json::from_slice(summary)?);
+
+    // the summary amounts are f32
+    let tolerance = expected_amount * f32::EPSILON as f64 * 4.0 + 0.01;
+    let consistent = count == expected_count && (amount - expected_amount).abs() <= tolerance;
+
+    Ok(serde_json::json!({
+        "elapsedMs": elapsed.as_millis() as u64,
+        "throughput": results.payments.len() as f64 / elapsed.as_secs_f64(),
+        "failed": results.failed,
+        "paymentsUs": latencies(&mut results.payments),
+        "summariesUs": latencies(&mut results.summaries),
+        "expected": { "totalRequests": expected_count, "totalAmount": expected_amount },
+        "summary": { "totalRequests": count, "totalAmount": amount },
+        "consistent": consistent,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_synthetic() {
+        let payments = synthetic(100, 1990);
+
+        let ids: std::collections::HashSet<_> = payments.iter().map(|p| &p.body).collect();
+        assert_eq!(ids.len(), 100);
+
+        let body: serde_json::Value = serde_json::from_str(&payments[0].body).unwrap();
+        let id = body["correlationId"].as_str().unwrap();
+        assert!(data::uuid_to_u128(id).is_some());
+        assert_eq!(&id[14..15], "4");
+        assert_eq!(body["amount"], 19.9);
+    }
+
+    #[test]
+    fn test_report() {
+        let results = || Results {
+            payments: vec![300, 100, 200],
+            summaries: vec![],
+            accepted: vec![1990, 1990],
+            failed: 1,
+        };
+
+        let summary = br#"{"BRL":{"default":{"totalRequests":1,"totalAmount":19.9},"fallback":{"totalRequests":1,"totalAmount":19.9}},"USD":{"default":{"totalRequests":0,"totalAmount":0}}}"#;
+
+        let consistent = report(results(), Duration::from_secs(1), summary).unwrap();
+
+        assert_eq!(consistent["consistent"], true);
+        assert_eq!(consistent["paymentsUs"]["p50"], 200);
+        assert_eq!(consistent["paymentsUs"]["max"], 300);
+        assert_eq!(consistent["summariesUs"]["count"], 0);
+
+        let summary = br#"{"default":{"totalRequests":2,"totalAmount":19.9}}"#;
+        let missing = report(results(), Duration::from_secs(1), summary).unwrap();
+
+        assert_eq!(missing["consistent"], false);
+    }
+
+    #[test]
+    fn test_target() {
+        assert_eq!(
+            Target::parse("unix:/var/run/api0.sock"),
+            Target::Unix("/var/run/api0.sock".into())
+        );
+        assert_eq!(
+            Target::parse("localhost:9999"),
+            Target::Tcp("localhost:9999".to_string())
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Checks the served summary against the mock processors' own records.
+
+use std::{path::Path, time::Duration};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use reqwest::Client;
+use tokio::task::JoinSet;
+
+use crate::{
+    api::{self, summary::Scope},
+    bench::Target,
+    data, db,
+    mock::MockProcessor,
+    shutdown::Shutdown,
+    worker::{self, pp_client::ProcessorConfig},
+};
+
+/// xorshift
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+
+        self.0 % n
+    }
+
+    fn uuid(&mut self) -> String {
+        let id = (self.below(u64::MAX) as u128) << 64 | self.below(u64::MAX) as u128;
+
+        // version 4, variant 1
+        data::u128_to_uuid((id & !(0xf << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62))
+    }
+}
+
+fn socket_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("rinha-{}-{name}.sock", std::process::id()));
+
+    path.display().to_string()
+}
+
+async fn wait_for(path: &str) {
+    while !Path::new(path).exists() {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+/// Sends `count` payments of up to R$ 99.99 through one API connection,
+/// returning how many were accepted.
+async fn drive(api_socket: String, seed: u64, count: usize) -> u64 {
+    let mut conn = Target::Unix(api_socket.into())
+        .connect()
+        .await
+        .expect("connect");
+    let mut rng = Rng(seed);
+    let mut accepted = 0;
+
+    for _ in 0..count {
+        let cents = 1 + rng.below(9_999);
+        let body = format!(
+            r#"{{"correlationId":"{}","amount":{}.{:02}}}"#,
+            rng.uuid(),
+            cents / 100,
+            cents % 100
+        );
+
+        let (status, _) = conn
+            .request("POST", "/payments", body.as_bytes())
+            .await
+            .expect("payment");
+
+        if (200..300).contains(&status) {
+            accepted += 1;
+        }
+
+        tokio::time::sleep(Duration::from_millis(rng.below(3))).await;
+    }
+
+    accepted
+}
+
+async fn stored(store: &db::Store, range: (i64, i64)) -> u64 {
+    let summary = store.get(range, &Scope::All).await;
+
+    summary[0].processors.iter().map(|(_, p)| p.count).sum()
+}
+
+#[tokio::test]
+async fn test_summary_matches_processors() {
+    let mocks = [
+        ("default", MockProcessor::new(0.05)),
+        ("fallback", MockProcessor::new(0.15)),
+    ];
+
+    let mut processors = Vec::new();
+    for (name, mock) in &mocks {
+        mock.set_delay(Duration::from_millis(5));
+        mock.set_failure_rate(0.1);
+
+        processors.push(ProcessorConfig {
+            name: name.to_string(),
+            url: mock.listen("127.0.0.1:0").await.expect("listen"),
+            fee: Some(mock.fee),
+            weight: 1,
+        });
+    }
+
+    let worker_socket = socket_path("e2e-worker");
+    let api_socket = socket_path("e2e-api");
+
+    let store = db::Store::new(processors.iter().map(|p| p.name.clone()).collect());
+    let (stop, shutdown) = Shutdown::channel();
+
+    let worker = tokio::spawn({
+        let processors = processors.clone();
+        let store = store.clone();
+        let socket = worker_socket.clone();
+        let shutdown = shutdown.clone();
+        async move { worker::run(&processors, store, &socket, shutdown).await }
+    });
+    wait_for(&worker_socket).await;
+
+    let api = tokio::spawn({
+        let socket = api_socket.clone();
+        async move { api::run(&socket, &worker_socket, shutdown).await }
+    });
+    wait_for(&api_socket).await;
+
+    let start = Utc::now().timestamp_micros();
+
+    let mut load = JoinSet::new();
+    for seed in 1..=8 {
+        load.spawn(drive(api_socket.clone(), seed * 7919, 50));
+    }
+
+    // an outage of the default processor, then a slow fallback
+    let (default, fallback) = (&mocks[0].1, &mocks[1].1);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    default.set_failing(true);
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    default.set_failing(false);
+    fallback.set_delay(Duration::from_millis(40));
+
+    let accepted: u64 = load.join_all().await.into_iter().sum();
+    assert!(accepted > 0);
+
+    let settle = Utc::now();
+    while stored(&store, (i64::MIN, i64::MAX)).await < accepted {
+        assert!(
+            Utc::now() - settle < chrono::Duration::seconds(10),
+            "not settled"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let end = Utc::now().timestamp_micros();
+
+    assert_eq!(
+        mocks.iter().map(|(_, m)| m.len() as u64).sum::<u64>(),
+        accepted
+    );
+
+    let mut rng = Rng(end as u64 | 1);
+    let mut windows = vec![(start, end)];
+    for _ in 0..100 {
+        let from = start + rng.below((end - start) as u64) as i64;
+        let to = from + rng.below((end - from) as u64) as i64;
+        windows.push((from, to));
+    }
+
+    let client = Client::new();
+    let date = |micros| {
+        DateTime::from_timestamp_micros(micros)
+            .expect("date")
+            .to_rfc3339_opts(SecondsFormat::Micros, true)
+    };
+
+    for (from, to) in windows {
+        let summary = store.get((from, to), &Scope::All).await;
+
+        for (name, processor) in mocks.iter().map(|(n, _)| n).zip(&processors) {
+            let ours = summary[0].get(name).expect("processor");
+
+            let theirs: serde_json::Value = client
+                .get(format!("{}/admin/payments-summary", processor.url))
+                .query(&[("from", date(from)), ("to", date(to))])
+                .header("X-Rinha-Token", "123")
+                .send()
+                .await
+                .expect("admin summary")
+                .json()
+                .await
+                .expect("json");
+
+            let cents = |amount: f64| (amount * 100.0).round() as u64;
+
+            assert_eq!(
+                (ours.count, cents(ours.amount as f64)),
+                (
+                    theirs["totalRequests"].as_u64().expect("count"),
+                    cents(theirs["totalAmount"].as_f64().expect("amount"))
+                ),
+                "{name} between {} and {}",
+                date(from),
+                date(to)
+            );
+        }
+    }
+
+    stop.send(true).ok();
+    worker.await.expect("worker").expect("worker run");
+    api.await.expect("api").expect("api run");
+}
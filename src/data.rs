@@ -1,4 +1,6 @@
-use anyhow::Result;
+use std::{str::FromStr, sync::OnceLock};
+
+use anyhow::{Result, anyhow};
 use bincode::{
     config::*,
     error::{DecodeError, EncodeError},
@@ -13,7 +15,11 @@ fn encode<S: serde::Serialize>(input: S, buf: &mut [u8]) -> Result<usize, Encode
     bincode::serde::encode_into_slice(&input, buf, CONFIG)
 }
 
-fn decode<D: serde::de::DeserializeOwned>(input: &[u8]) -> Result<D, DecodeError> {
+pub fn encode_to_vec<S: serde::Serialize>(input: S) -> Result<Vec<u8>, EncodeError> {
+    bincode::serde::encode_to_vec(&input, CONFIG)
+}
+
+pub fn decode<D: serde::de::DeserializeOwned>(input: &[u8]) -> Result<D, DecodeError> {
     let (o, _) = bincode::serde::borrow_decode_from_slice(input, CONFIG)?;
 
     Ok(o)
@@ -34,50 +40,101 @@ pub async fn send<T: serde::Serialize, S: AsyncWriteExt + Unpin>(
     Ok(())
 }
 
+/// Like [`send`], for payloads too big or unpredictable for a stack buffer.
+pub async fn send_large<T: serde::Serialize, S: AsyncWriteExt + Unpin>(
+    payload: T,
+    stream: &mut S,
+) -> Result<()> {
+    let payload = encode_to_vec(payload)?;
+
+    stream.write_all(&payload.len().to_be_bytes()).await?;
+    stream.write_all(&payload).await?;
+
+    Ok(())
+}
+
+/// Reads a single frame written by [`send`] or [`send_large`].
+pub async fn recv<T: serde::de::DeserializeOwned, S: AsyncReadExt + Unpin>(
+    stream: &mut S,
+) -> Result<T> {
+    let mut size = [0u8; SIZE];
+    stream.read_exact(&mut size).await?;
+
+    let mut payload = vec![0u8; usize::from_be_bytes(size)];
+    stream.read_exact(&mut payload).await?;
+
+    Ok(decode(&payload)?)
+}
+
+/// Reads frames written by [`send`] and [`send_large`], growing its buffer
+/// for frames bigger than it and keeping partial frames for the next read.
 pub struct FramedStream<S: AsyncReadExt + Unpin> {
-    buf: [u8; 1024],
+    buf: Vec<u8>,
     stream: S,
-    offset: usize,
+    start: usize,
+    end: usize,
 }
 
 impl<S: AsyncReadExt + Unpin> FramedStream<S> {
     pub fn new(stream: S) -> Self {
         Self {
-            buf: [0u8; 1024],
+            buf: vec![0u8; 1024],
             stream,
-            offset: 0,
+            start: 0,
+            end: 0,
         }
     }
 
     pub async fn read(&mut self) -> Result<usize> {
-        let read = self.stream.read(&mut self.buf).await?;
-
-        if read == 0 {
-            return Ok(0);
+        if self.end == self.buf.len() {
+            match self.start {
+                0 => self.buf.resize(self.buf.len() * 2, 0),
+                start => {
+                    self.buf.copy_within(start..self.end, 0);
+                    self.end -= start;
+                    self.start = 0;
+                }
+            }
         }
 
+        let read = self.stream.read(&mut self.buf[self.end..]).await?;
+
+        self.end += read;
+
         Ok(read)
     }
 
+    /// The next complete frame, `None` until more is read.
     pub fn next<T: serde::de::DeserializeOwned>(&mut self) -> Result<Option<T>> {
-        if self.offset + SIZE > self.buf.len() {
-            // unix sockets should not have partial reads
-            panic!("buffer overflow!");
+        let available = &self.buf[self.start..self.end];
+
+        if available.len() < SIZE {
+            return Ok(None);
         }
 
-        let payload_size =
-            usize::from_be_bytes(self.buf[self.offset..self.offset + SIZE].try_into()?);
+        let payload_size = usize::from_be_bytes(available[..SIZE].try_into()?);
+        let frame_size = SIZE + payload_size;
+
+        if available.len() < frame_size {
+            // make room for the whole frame
+            if self.buf.len() - self.start < frame_size {
+                self.buf.copy_within(self.start..self.end, 0);
+                self.end -= self.start;
+                self.start = 0;
+                self.buf.resize(self.buf.len().max(frame_size), 0);
+            }
 
-        if payload_size == 0 {
-            self.offset = 0;
-            self.buf.fill(0);
             return Ok(None);
         }
 
-        let payload_offset = self.offset + SIZE;
-        let payload = decode(&self.buf[payload_offset..payload_offset + payload_size])?;
+        let payload = decode(&available[SIZE..frame_size])?;
+
+        self.start += frame_size;
 
-        self.offset = payload_offset + payload_size;
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
 
         Ok(Some(payload))
     }
@@ -87,16 +144,218 @@ impl<S: AsyncReadExt + Unpin> FramedStream<S> {
     }
 }
 
+/// Ledger entry: a processed payment, or the refund of one when `refund`.
+#[derive(Debug, Clone, Copy)]
 pub struct Payment {
+    pub correlation_id: u128,
     pub amount: u64,
     pub requested_at: i64,
     pub processor_id: u8,
+    /// Interned by [`crate::db::Store::merchant_id`].
+    pub merchant_id: u32,
+    pub currency: Currency,
+    pub refund: bool,
 }
 
 #[derive(Debug, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ProcessorPaymentRequest {
     pub requested_at: DateTime<Utc>,
-    pub amount: f32,
+    #[serde(serialize_with = "cents::serialize")]
+    pub amount: u64,
+    pub correlation_id: String,
+    pub currency: Currency,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessorRefundRequest {
+    pub requested_at: DateTime<Utc>,
+    #[serde(serialize_with = "cents::serialize")]
+    pub amount: u64,
     pub correlation_id: String,
+    pub currency: Currency,
+}
+
+/// Amounts are kept in cents, and only written as decimals in JSON.
+pub mod cents {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn to_decimal(cents: u64) -> f64 {
+        cents as f64 / 100.0
+    }
+
+    pub fn serialize<S: Serializer>(cents: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(to_decimal(*cents))
+    }
+
+    /// Rounded, as 19.9 * 100.0 is just below 1990. Negative amounts are 0.
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let amount = f64::deserialize(deserializer)?;
+
+        Ok((amount * 100.0).round() as u64)
+    }
+}
+
+/// Parses a hyphenated UUID into its 128 bits.
+pub fn uuid_to_u128(id: &str) -> Option<u128> {
+    let valid = id.len() == 36
+        && id.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+
+    if !valid {
+        return None;
+    }
+
+    u128::from_str_radix(&id.replace('-', ""), 16).ok()
+}
+
+/// Inverse of [`uuid_to_u128`], lowercase.
+pub fn u128_to_uuid(id: u128) -> String {
+    let hex = format!("{id:032x}");
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// ISO 4217 code, e.g. `BRL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        // only built from ascii letters
+        std::str::from_utf8(&self.0).expect("ascii currency")
+    }
+
+    /// `ALLOWED_CURRENCIES` (`BRL,USD,...`), `BRL` by default. The first one
+    /// is used for payments without a currency.
+    pub fn allowed() -> &'static [Currency] {
+        static ALLOWED: OnceLock<Vec<Currency>> = OnceLock::new();
+
+        ALLOWED.get_or_init(|| {
+            let allowed = std::env::var("ALLOWED_CURRENCIES").unwrap_or("BRL".to_string());
+
+            let mut currencies = Vec::new();
+
+            for code in allowed.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+                match code.parse() {
+                    Ok(currency) if !currencies.contains(&currency) => currencies.push(currency),
+                    Ok(_) => {}
+                    Err(err) => tracing::warn!(?err, "ignoring currency {code:?}"),
+                }
+            }
+
+            if currencies.is_empty() {
+                currencies.push(Currency(*b"BRL"));
+            }
+
+            currencies
+        })
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Currency::allowed()[0]
+    }
+}
+
+impl FromStr for Currency {
+    type Err = anyhow::Error;
+
+    fn from_str(code: &str) -> Result<Self> {
+        let code: [u8; 3] = code
+            .as_bytes()
+            .try_into()
+            .map_err(|_| anyhow!("currency must have 3 letters"))?;
+
+        if !code.iter().all(u8::is_ascii_uppercase) {
+            return Err(anyhow!("currency must be uppercase letters"));
+        }
+
+        Ok(Currency(code))
+    }
+}
+
+impl std::fmt::Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl serde::Serialize for Currency {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Currency {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = <std::borrow::Cow<str>>::deserialize(deserializer)?;
+
+        code.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_framed_stream() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut stream = FramedStream::new(reader);
+
+        let big = "x".repeat(5_000);
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            send("small".to_string(), &mut buf, &mut writer)
+                .await
+                .unwrap();
+            send_large(big, &mut writer).await.unwrap();
+            send("after".to_string(), &mut buf, &mut writer)
+                .await
+                .unwrap();
+        });
+
+        let mut frames: Vec<String> = Vec::new();
+
+        while frames.len() < 3 {
+            assert!(stream.read().await.unwrap() > 0);
+
+            while let Some(frame) = stream.next().unwrap() {
+                frames.push(frame);
+            }
+        }
+
+        assert_eq!(frames[0], "small");
+        assert_eq!(frames[1].len(), 5_000);
+        assert_eq!(frames[2], "after");
+    }
+
+    #[test]
+    fn test_uuid() {
+        let id = "4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3";
+
+        let bits = uuid_to_u128(id).expect("valid uuid");
+        assert_eq!(bits, 0x4a7901b87d264d9daa194dc1c7cf60b3);
+        assert_eq!(u128_to_uuid(bits), id);
+
+        assert_eq!(
+            uuid_to_u128("4A7901B8-7D26-4D9D-AA19-4DC1C7CF60B3"),
+            Some(bits)
+        );
+        assert_eq!(uuid_to_u128("4a7901b87d264d9daa194dc1c7cf60b3"), None);
+        assert_eq!(uuid_to_u128("4a7901b8-7d26-4d9d-aa19-4dc1c7cf60bz"), None);
+    }
 }
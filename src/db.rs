@@ -1,19 +1,123 @@
-use std::{sync::Arc, time::Instant};
+use std::{collections::HashMap, sync::Arc, time::Instant};
 
 use metrics::Unit;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, broadcast};
 
-use crate::{api::summary::Summary, data::Payment};
+use crate::{
+    api::summary::{Scope, Summary, Totals},
+    data::{Currency, Payment},
+};
+
+/// Merchant id of payments sent without one.
+pub const NO_MERCHANT: u32 = 0;
+
+/// Changes kept for slow summary subscribers before they have to start over.
+const CHANGES_CAPACITY: usize = 4096;
+
+/// Totals of each processor, per currency.
+pub type CurrencyTotals = Vec<(Currency, Vec<Totals>)>;
+
+/// A ledger change, as seen by summary subscribers.
+#[derive(Debug, Clone, Copy)]
+pub enum Change {
+    Inserted(Payment),
+    /// Payments were purged or imported, subscribers need a new snapshot.
+    Reset,
+}
 
 #[derive(Clone)]
 pub struct Store {
     payments: Arc<RwLock<Vec<Payment>>>,
+    processors: Arc<[String]>,
+    merchants: Arc<std::sync::RwLock<Merchants>>,
+    /// Processed payments by correlation id, for refunds.
+    index: Arc<std::sync::Mutex<HashMap<u128, Indexed>>>,
+    changes: broadcast::Sender<Change>,
+}
+
+struct Indexed {
+    payment: Payment,
+    refund: RefundState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RefundState {
+    None,
+    InFlight,
+    Done,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RefundError {
+    /// Unknown, not processed yet or from another merchant.
+    NotFound,
+    AlreadyRefunded,
+}
+
+/// Interns merchant names so each payment only keeps an id.
+struct Merchants {
+    ids: HashMap<String, u32>,
+    names: Vec<String>,
 }
 
 impl Store {
-    pub fn new() -> Self {
+    pub fn new(processors: Vec<String>) -> Self {
+        let merchants = Merchants {
+            ids: HashMap::new(),
+            names: vec![String::new()],
+        };
+
         Self {
             payments: Arc::new(RwLock::new(Vec::with_capacity(100_000))),
+            processors: processors.into(),
+            merchants: Arc::new(std::sync::RwLock::new(merchants)),
+            index: Arc::default(),
+            changes: broadcast::Sender::new(CHANGES_CAPACITY),
+        }
+    }
+
+    /// Id stored with `merchant`'s payments, assigned on first use.
+    pub fn merchant_id(&self, merchant: Option<&str>) -> u32 {
+        let Some(merchant) = merchant else {
+            return NO_MERCHANT;
+        };
+
+        if let Some(id) = self.merchants.read().unwrap().ids.get(merchant) {
+            return *id;
+        }
+
+        let mut merchants = self.merchants.write().unwrap();
+
+        if let Some(id) = merchants.ids.get(merchant) {
+            return *id;
+        }
+
+        let id = merchants.names.len() as u32;
+        merchants.names.push(merchant.to_string());
+        merchants.ids.insert(merchant.to_string(), id);
+
+        id
+    }
+
+    /// `None` for payments sent without a merchant.
+    pub fn merchant_name(&self, id: u32) -> Option<String> {
+        match id {
+            NO_MERCHANT => None,
+            id => self
+                .merchants
+                .read()
+                .unwrap()
+                .names
+                .get(id as usize)
+                .cloned(),
+        }
+    }
+
+    /// Id of an already known merchant, without assigning one.
+    fn find_merchant(&self, merchant: Option<&str>) -> Option<u32> {
+        match merchant {
+            None => Some(NO_MERCHANT),
+            Some(merchant) => self.merchants.read().unwrap().ids.get(merchant).copied(),
         }
     }
 
@@ -21,43 +125,529 @@ impl Store {
         let now = Instant::now();
 
         {
-            self.payments.write().await.push(payment);
+            let mut payments = self.payments.write().await;
+
+            // payments answered out of order still keep the ledger sorted by
+            // `requested_at`, which the range queries binary search on
+            let at = payments.partition_point(|p| p.requested_at <= payment.requested_at);
+            payments.insert(at, payment);
+
+            // under the lock, so a subscriber's snapshot never counts it twice
+            self.changes.send(Change::Inserted(payment)).ok();
         }
 
+        index(&mut self.index.lock().unwrap(), payment);
+
         metrics::describe_histogram!("db.insert", Unit::Nanoseconds, "db insert time");
         metrics::histogram!("db.insert").record(now.elapsed().as_nanos() as f64);
     }
 
-    pub async fn get(&self, (from, to): (i64, i64)) -> Summary {
-        let now = Instant::now();
+    /// Adds historic payments, keeping the ledger ordered by `requested_at`.
+    pub async fn insert_bulk(&self, mut batch: Vec<Payment>) {
+        batch.sort_by_key(|p| p.requested_at);
 
-        let summary = {
-            let payments = self.payments.read().await;
+        {
+            let mut payments = self.payments.write().await;
+            merge(&mut payments, &batch);
 
-            let start = payments
-                .binary_search_by_key(&from, |p| p.requested_at)
-                .unwrap_or_else(|pos| pos);
+            self.changes.send(Change::Reset).ok();
+        }
 
-            let end = payments
-                .binary_search_by_key(&to, |p| p.requested_at)
-                .map_or_else(|pos| pos, |pos| pos + 1);
+        let mut index = self.index.lock().unwrap();
 
-            payments[start..end]
-                .iter()
-                .fold([(0, 0), (0, 0)], |mut acc, p| {
-                    acc[p.processor_id as usize].0 += 1;
-                    acc[p.processor_id as usize].1 += p.amount;
-                    acc
-                })
+        for payment in batch {
+            self::index(&mut index, payment);
+        }
+    }
+
+    /// Whether a processed payment with that id is stored.
+    pub fn contains(&self, correlation_id: u128) -> bool {
+        self.index.lock().unwrap().contains_key(&correlation_id)
+    }
+
+    /// Totals per currency, every allowed currency first (zeroed when unused)
+    /// followed by any other one still stored.
+    pub async fn get(&self, range: (i64, i64), scope: &Scope) -> Vec<Summary> {
+        let payments = self.payments.read().await;
+
+        let totals = self.totals(&payments, range, scope);
+
+        self.summaries(totals)
+    }
+
+    /// Like [`Store::get`] over every payment, along with the changes made
+    /// after it.
+    pub async fn subscribe(&self, scope: &Scope) -> (CurrencyTotals, broadcast::Receiver<Change>) {
+        let payments = self.payments.read().await;
+
+        let totals = self.totals(&payments, (i64::MIN, i64::MAX), scope);
+
+        (totals, self.changes.subscribe())
+    }
+
+    fn totals(
+        &self,
+        payments: &[Payment],
+        (from, to): (i64, i64),
+        scope: &Scope,
+    ) -> CurrencyTotals {
+        let now = Instant::now();
+
+        let mut totals: CurrencyTotals = Currency::allowed()
+            .iter()
+            .map(|currency| (*currency, vec![Totals::default(); self.processors.len()]))
+            .collect();
+
+        let merchant = match scope {
+            Scope::All => None,
+            Scope::Merchant(merchant) => match self.find_merchant(merchant.as_deref()) {
+                Some(id) => Some(id),
+                // never sent a payment
+                None => return totals,
+            },
         };
 
+        let (start, end) = bounds(payments, Some((from, to)));
+
+        let selected = payments[start..end]
+            .iter()
+            .filter(|p| merchant.is_none_or(|id| p.merchant_id == id));
+
+        for p in selected {
+            self.accumulate(&mut totals, p);
+        }
+
         metrics::describe_histogram!("db.select", Unit::Nanoseconds, "db query time");
         metrics::histogram!("db.select").record(now.elapsed().as_nanos() as f64);
 
-        Summary::new([summary[0], summary[1]])
+        totals
+    }
+
+    /// Adds `payment` to its currency and processor in `totals`.
+    pub fn accumulate(&self, totals: &mut CurrencyTotals, payment: &Payment) {
+        let i = match totals.iter().position(|(c, _)| *c == payment.currency) {
+            Some(i) => i,
+            None => {
+                let empty = vec![Totals::default(); self.processors.len()];
+                totals.push((payment.currency, empty));
+                totals.len() - 1
+            }
+        };
+
+        let acc = &mut totals[i].1[payment.processor_id as usize];
+
+        match payment.refund {
+            true => {
+                acc.refunds += 1;
+                acc.refunded += payment.amount;
+            }
+            false => {
+                acc.count += 1;
+                acc.amount += payment.amount;
+            }
+        }
+    }
+
+    fn summaries(&self, totals: CurrencyTotals) -> Vec<Summary> {
+        totals
+            .into_iter()
+            .map(|(currency, summary)| Summary::new(&self.processors, currency, summary))
+            .collect()
+    }
+
+    /// The first `limit` payments within `range` (inclusive) and `scope`, in
+    /// order, plus any other sharing the last one's `requested_at`, so the
+    /// next page can start right after it.
+    pub async fn page(&self, (from, to): (i64, i64), scope: &Scope, limit: usize) -> Vec<Payment> {
+        let merchant = match scope {
+            Scope::All => None,
+            Scope::Merchant(merchant) => match self.find_merchant(merchant.as_deref()) {
+                Some(id) => Some(id),
+                None => return Vec::new(),
+            },
+        };
+
+        let payments = self.payments.read().await;
+        let (start, end) = bounds(&payments, Some((from, to)));
+
+        let mut page: Vec<Payment> = Vec::with_capacity(limit);
+
+        let selected = payments[start..end]
+            .iter()
+            .filter(|p| merchant.is_none_or(|id| p.merchant_id == id));
+
+        for p in selected {
+            if page.len() >= limit
+                && page
+                    .last()
+                    .is_some_and(|l| l.requested_at != p.requested_at)
+            {
+                break;
+            }
+
+            page.push(*p);
+        }
+
+        page
+    }
+
+    /// Counts the payments requested within `range` (inclusive), all of them on `None`.
+    pub async fn count(&self, range: Option<(i64, i64)>) -> usize {
+        let payments = self.payments.read().await;
+
+        let (start, end) = bounds(&payments, range);
+
+        end - start
+    }
+
+    /// Removes and returns the payments requested within `range` (inclusive),
+    /// all of them on `None`.
+    pub async fn purge(&self, range: Option<(i64, i64)>) -> Vec<Payment> {
+        let mut payments = self.payments.write().await;
+
+        let (start, end) = bounds(&payments, range);
+
+        let purged: Vec<_> = payments.drain(start..end).collect();
+
+        if !purged.is_empty() {
+            self.changes.send(Change::Reset).ok();
+        }
+
+        let mut index = self.index.lock().unwrap();
+        for p in purged.iter().filter(|p| !p.refund) {
+            index.remove(&p.correlation_id);
+        }
+
+        purged
     }
 
-    pub async fn purge(&self) {
-        self.payments.write().await.clear();
+    /// A processed payment within `scope`, and whether it was refunded.
+    pub fn find(&self, correlation_id: u128, scope: &Scope) -> Option<(Payment, bool)> {
+        let merchant = match scope {
+            Scope::All => None,
+            Scope::Merchant(merchant) => Some(self.find_merchant(merchant.as_deref())?),
+        };
+
+        let index = self.index.lock().unwrap();
+
+        index
+            .get(&correlation_id)
+            .filter(|i| merchant.is_none_or(|id| i.payment.merchant_id == id))
+            .map(|i| (i.payment, i.refund == RefundState::Done))
+    }
+
+    /// Reserves the refund of a processed payment within `scope`, returning
+    /// it. Must be followed by inserting the refund or [`Store::cancel_refund`].
+    pub fn start_refund(
+        &self,
+        correlation_id: u128,
+        scope: &Scope,
+    ) -> Result<Payment, RefundError> {
+        let merchant = match scope {
+            Scope::All => None,
+            Scope::Merchant(merchant) => Some(
+                self.find_merchant(merchant.as_deref())
+                    .ok_or(RefundError::NotFound)?,
+            ),
+        };
+
+        let mut index = self.index.lock().unwrap();
+
+        let indexed = index
+            .get_mut(&correlation_id)
+            .filter(|i| merchant.is_none_or(|id| i.payment.merchant_id == id))
+            .ok_or(RefundError::NotFound)?;
+
+        if indexed.refund != RefundState::None {
+            return Err(RefundError::AlreadyRefunded);
+        }
+
+        indexed.refund = RefundState::InFlight;
+
+        Ok(indexed.payment)
+    }
+
+    /// Releases a refund reserved by [`Store::start_refund`] that failed.
+    pub fn cancel_refund(&self, correlation_id: u128) {
+        let mut index = self.index.lock().unwrap();
+
+        if let Some(indexed) = index.get_mut(&correlation_id) {
+            indexed.refund = RefundState::None;
+        }
+    }
+
+    pub fn processor_name(&self, id: u8) -> &str {
+        &self.processors[id as usize]
+    }
+
+    pub fn processors(&self) -> &[String] {
+        &self.processors
+    }
+}
+
+/// Tracks `payment` for refunds.
+fn index(index: &mut HashMap<u128, Indexed>, payment: Payment) {
+    match payment.refund {
+        true => {
+            if let Some(indexed) = index.get_mut(&payment.correlation_id) {
+                indexed.refund = RefundState::Done;
+            }
+        }
+        false => {
+            let indexed = Indexed {
+                payment,
+                refund: RefundState::None,
+            };
+            index.insert(payment.correlation_id, indexed);
+        }
+    }
+}
+
+/// Merges the sorted `batch` into the sorted `payments` from the back, in
+/// place, each one after those already stored with the same `requested_at`.
+fn merge(payments: &mut Vec<Payment>, batch: &[Payment]) {
+    let (mut i, mut j) = (payments.len(), batch.len());
+
+    payments.extend_from_slice(batch);
+
+    for k in (0..payments.len()).rev() {
+        if j == 0 {
+            break;
+        }
+
+        if i > 0 && payments[i - 1].requested_at > batch[j - 1].requested_at {
+            payments[k] = payments[i - 1];
+            i -= 1;
+        } else {
+            payments[k] = batch[j - 1];
+            j -= 1;
+        }
+    }
+}
+
+fn bounds(payments: &[Payment], range: Option<(i64, i64)>) -> (usize, usize) {
+    let Some((from, to)) = range else {
+        return (0, payments.len());
+    };
+
+    let start = payments.partition_point(|p| p.requested_at < from);
+    let end = payments.partition_point(|p| p.requested_at <= to);
+
+    (start, end.max(start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payment(requested_at: i64) -> Payment {
+        Payment {
+            correlation_id: requested_at as u128,
+            amount: 1990,
+            requested_at,
+            processor_id: 0,
+            merchant_id: NO_MERCHANT,
+            currency: Currency::default(),
+            refund: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_purge_range() {
+        let store = Store::new(vec!["default".to_string()]);
+
+        for requested_at in [10, 20, 20, 30, 40] {
+            store.insert(payment(requested_at)).await;
+        }
+
+        assert_eq!(store.count(Some((20, 30))).await, 3);
+        assert_eq!(store.count(Some((41, 50))).await, 0);
+
+        let purged = store.purge(Some((15, 30))).await;
+        assert_eq!(purged.len(), 3);
+        assert_eq!(store.count(None).await, 2);
+
+        let summary = store.get((0, 100), &Scope::All).await;
+        assert_eq!(summary[0].processors[0].1.count, 2);
+
+        assert_eq!(store.purge(None).await.len(), 2);
+        assert_eq!(store.count(None).await, 0);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut payments: Vec<_> = [10, 20, 30].map(payment).into();
+        let batch = [5, 20, 25, 40].map(|at| Payment {
+            refund: true,
+            ..payment(at)
+        });
+
+        merge(&mut payments, &batch);
+
+        let order: Vec<_> = payments
+            .iter()
+            .map(|p| (p.requested_at, p.refund))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                (5, true),
+                (10, false),
+                (20, false),
+                (20, true),
+                (25, true),
+                (30, false),
+                (40, true)
+            ]
+        );
+
+        let mut empty = Vec::new();
+        merge(&mut empty, &batch);
+        assert_eq!(empty.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_window_edges() {
+        let store = Store::new(vec!["default".to_string()]);
+
+        for requested_at in [10, 20, 20, 20, 20, 30, 30, 30, 40] {
+            store.insert(payment(requested_at)).await;
+        }
+
+        let count = async |range| store.get(range, &Scope::All).await[0].processors[0].1.count;
+
+        assert_eq!(count((20, 30)).await, 7);
+        assert_eq!(count((20, 20)).await, 4);
+        assert_eq!(count((21, 29)).await, 0);
+        assert_eq!(count((30, 20)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_merchant_scope() {
+        let store = Store::new(vec!["default".to_string()]);
+
+        let a = store.merchant_id(Some("a"));
+        let b = store.merchant_id(Some("b"));
+        assert_eq!(store.merchant_id(Some("a")), a);
+        assert_ne!(a, b);
+        assert_eq!(store.merchant_name(b).as_deref(), Some("b"));
+
+        for (requested_at, merchant_id) in [(10, a), (20, b), (30, b), (40, NO_MERCHANT)] {
+            store
+                .insert(Payment {
+                    merchant_id,
+                    ..payment(requested_at)
+                })
+                .await;
+        }
+
+        let count = |scope: Scope| {
+            let store = store.clone();
+            async move { store.get((0, 100), &scope).await[0].processors[0].1.count }
+        };
+
+        assert_eq!(count(Scope::Merchant(Some("a".to_string()))).await, 1);
+        assert_eq!(count(Scope::Merchant(Some("b".to_string()))).await, 2);
+        assert_eq!(count(Scope::Merchant(None)).await, 1);
+        assert_eq!(count(Scope::Merchant(Some("c".to_string()))).await, 0);
+        assert_eq!(count(Scope::All).await, 4);
+    }
+
+    #[tokio::test]
+    async fn test_currencies() {
+        let store = Store::new(vec!["default".to_string(), "fallback".to_string()]);
+
+        let usd = "USD".parse().unwrap();
+
+        store.insert(payment(10)).await;
+        store
+            .insert(Payment {
+                currency: usd,
+                processor_id: 1,
+                ..payment(20)
+            })
+            .await;
+
+        let summaries = store.get((0, 100), &Scope::All).await;
+
+        // BRL is the only allowed currency, USD still shows up once stored
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].currency, Currency::default());
+        assert_eq!(summaries[0].get("default").unwrap().count, 1);
+        assert_eq!(summaries[0].get("fallback").unwrap().count, 0);
+        assert_eq!(summaries[1].currency, usd);
+        assert_eq!(summaries[1].get("fallback").unwrap().count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_refund() {
+        let store = Store::new(vec!["default".to_string()]);
+
+        let shop = store.merchant_id(Some("shop"));
+        store
+            .insert(Payment {
+                merchant_id: shop,
+                ..payment(10)
+            })
+            .await;
+
+        let other = Scope::Merchant(Some("other".to_string()));
+        let own = Scope::Merchant(Some("shop".to_string()));
+
+        assert_eq!(
+            store.start_refund(99, &Scope::All).unwrap_err(),
+            RefundError::NotFound
+        );
+        assert_eq!(
+            store.start_refund(10, &other).unwrap_err(),
+            RefundError::NotFound
+        );
+
+        assert!(store.find(10, &other).is_none());
+        assert_eq!(
+            store.find(10, &own).map(|(_, refunded)| refunded),
+            Some(false)
+        );
+
+        let original = store.start_refund(10, &own).expect("refundable");
+        assert_eq!(original.amount, 1990);
+        assert_eq!(
+            store.start_refund(10, &Scope::All).unwrap_err(),
+            RefundError::AlreadyRefunded
+        );
+
+        store.cancel_refund(10);
+        store
+            .start_refund(10, &Scope::All)
+            .expect("refundable again");
+
+        store
+            .insert(Payment {
+                requested_at: 20,
+                refund: true,
+                ..original
+            })
+            .await;
+
+        assert_eq!(
+            store.start_refund(10, &Scope::All).unwrap_err(),
+            RefundError::AlreadyRefunded
+        );
+        assert_eq!(
+            store.find(10, &own).map(|(_, refunded)| refunded),
+            Some(true)
+        );
+
+        let summary = store.get((0, 100), &own).await;
+        let totals = summary[0].get("default").unwrap();
+        assert_eq!((totals.count, totals.amount), (1, 19.9));
+        assert_eq!(
+            (totals.refunds, totals.refunded, totals.net),
+            (1, 19.9, 0.0)
+        );
+
+        // refunds count when they happen, not when the payment did
+        let before = store.get((0, 15), &own).await;
+        assert_eq!(before[0].get("default").unwrap().refunds, 0);
     }
 }
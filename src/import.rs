@@ -0,0 +1,296 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use anyhow::{Result, anyhow};
+use chrono::DateTime;
+use tokio::net::UnixStream;
+
+use crate::{
+    api::{
+        payment::MAX_AMOUNT,
+        summary::{Query, Scope},
+    },
+    data::{self, Currency},
+    env_or, get_worker_socket,
+    worker::{
+        WorkerRequest,
+        import::{ImportRecord, ImportResult},
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Csv,
+    Ndjson,
+}
+
+/// A line as written by `GET /payments/export`, before validation.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawRecord {
+    requested_at: String,
+    correlation_id: String,
+    amount: f64,
+    currency: Option<String>,
+    processor: String,
+    merchant: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+/// Loads a CSV or NDJSON ledger (the export format) into the running worker,
+/// oldest payments first, and prints the resulting totals.
+#[tokio::main(flavor = "current_thread")]
+pub async fn serve(path: &Path) -> Result<()> {
+    let format = match path.extension().and_then(|e| e.to_str()) {
+        Some("csv") => Format::Csv,
+        _ => Format::Ndjson,
+    };
+
+    let batch_size: usize = env_or("IMPORT_BATCH_SIZE", 1_000);
+    if batch_size == 0 {
+        return Err(anyhow!("IMPORT_BATCH_SIZE must be at least 1"));
+    }
+
+    tracing::info!("importing {} as {format:?}", path.display());
+
+    let input = BufReader::new(File::open(path)?);
+    let Parsed {
+        mut records,
+        rejected,
+    } = parse(input, format)?;
+
+    for (line, reason) in &rejected {
+        tracing::warn!(line, "rejected: {reason}");
+    }
+
+    records.sort_by_key(|r| r.requested_at);
+
+    let mut worker = UnixStream::connect(get_worker_socket()).await?;
+    let mut imported = 0;
+    let mut refused = 0;
+
+    for batch in records.chunks(batch_size) {
+        data::send_large(WorkerRequest::Import(batch.to_vec()), &mut worker).await?;
+
+        let result: ImportResult = data::recv(&mut worker).await?;
+
+        for (correlation_id, reason) in &result.rejected {
+            let correlation_id = data::u128_to_uuid(*correlation_id);
+            tracing::warn!(correlation_id, "refused by the worker: {reason}");
+        }
+
+        imported += result.inserted;
+        refused += result.rejected.len();
+    }
+
+    tracing::info!(
+        read = records.len() + rejected.len(),
+        imported,
+        rejected = rejected.len() + refused,
+        "import finished"
+    );
+
+    let query = Query {
+        range: (i64::MIN, i64::MAX),
+        scope: Scope::All,
+    };
+    data::send_large(WorkerRequest::Summary(query), &mut worker).await?;
+
+    let totals: Vec<u8> = data::recv(&mut worker).await?;
+    println!("{}", String::from_utf8_lossy(&totals));
+
+    Ok(())
+}
+
+struct Parsed {
+    records: Vec<ImportRecord>,
+    /// Line number and reason of every invalid line.
+    rejected: Vec<(usize, String)>,
+}
+
+/// Reads `input` a line at a time, only the valid records are kept.
+fn parse(input: impl BufRead, format: Format) -> Result<Parsed> {
+    let mut columns = None;
+    let mut records = Vec::new();
+    let mut rejected = Vec::new();
+    let mut seen = HashSet::new();
+
+    for (i, text) in input.lines().enumerate() {
+        let (line, text) = (i + 1, text?);
+
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        let columns = match (format, &columns) {
+            (Format::Csv, None) => {
+                columns = Some(csv_fields(&text));
+                continue;
+            }
+            (_, columns) => columns.as_deref().unwrap_or_default(),
+        };
+
+        let raw = match format {
+            Format::Csv => csv_record(columns, &text),
+            Format::Ndjson => serde_json::from_str(&text).map_err(|err| err.to_string()),
+        };
+
+        let record = raw.and_then(validate).and_then(|record| {
+            match seen.insert((record.correlation_id, record.refund)) {
+                true => Ok(record),
+                false => Err("duplicate in file".to_string()),
+            }
+        });
+
+        match record {
+            Ok(record) => records.push(record),
+            Err(reason) => rejected.push((line, reason)),
+        }
+    }
+
+    if format == Format::Csv && columns.is_none() {
+        return Err(anyhow!("missing CSV header"));
+    }
+
+    Ok(Parsed { records, rejected })
+}
+
+fn csv_record(columns: &[String], line: &str) -> Result<RawRecord, String> {
+    let mut raw = RawRecord::default();
+
+    for (column, value) in columns.iter().zip(csv_fields(line)) {
+        let optional = || Some(value.clone()).filter(|v| !v.is_empty());
+
+        match column.as_str() {
+            "requestedAt" => raw.requested_at = value,
+            "correlationId" => raw.correlation_id = value,
+            "amount" => raw.amount = value.parse().map_err(|_| "invalid amount")?,
+            "currency" => raw.currency = optional(),
+            "processor" => raw.processor = value,
+            "merchant" => raw.merchant = optional(),
+            "type" => raw.kind = optional(),
+            _ => {}
+        }
+    }
+
+    Ok(raw)
+}
+
+/// Splits a CSV line, unquoting `"..."` fields and their `""` escapes.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+
+    fields.push(field);
+
+    fields
+}
+
+fn validate(raw: RawRecord) -> Result<ImportRecord, String> {
+    let correlation_id =
+        data::uuid_to_u128(&raw.correlation_id).ok_or("correlationId must be a UUID")?;
+
+    let cents = raw.amount * 100.0;
+    if !(cents.is_finite() && cents >= 1.0 && (cents - cents.round()).abs() < 1e-6) {
+        return Err("amount must be positive with at most two decimals".to_string());
+    }
+
+    if raw.amount > MAX_AMOUNT {
+        return Err(format!("amount must be at most {MAX_AMOUNT}"));
+    }
+
+    let requested_at = DateTime::parse_from_rfc3339(&raw.requested_at)
+        .map_err(|_| "requestedAt must be an RFC 3339 date")?
+        .timestamp_micros();
+
+    let currency = match raw.currency {
+        Some(code) => code.parse().map_err(|_| "invalid currency")?,
+        None => Currency::default(),
+    };
+
+    if raw.processor.is_empty() {
+        return Err("missing processor".to_string());
+    }
+
+    let refund = match raw.kind.as_deref() {
+        None | Some("payment") => false,
+        Some("refund") => true,
+        Some(kind) => return Err(format!("invalid type {kind:?}")),
+    };
+
+    Ok(ImportRecord {
+        correlation_id,
+        amount: cents.round() as u64,
+        requested_at,
+        currency,
+        processor: raw.processor,
+        merchant: raw.merchant,
+        refund,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() {
+        let input = r#"requestedAt,correlationId,amount,currency,processor,merchant,type
+2025-07-01T12:00:00.5Z,4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3,19.90,BRL,fallback,"acme, ""inc""",payment
+
+2025-07-01T12:00:01Z,4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3,19.90,BRL,fallback,,refund
+2025-07-01T12:00:02Z,4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3,19.90,BRL,fallback,,payment
+2025-07-01T12:00:03Z,not-a-uuid,19.90,BRL,default,,payment
+2025-07-01T12:00:04Z,5a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3,19.999,BRL,default,,payment
+"#;
+
+        let Parsed { records, rejected } = parse(input.as_bytes(), Format::Csv).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].amount, 1990);
+        assert_eq!(records[0].merchant.as_deref(), Some(r#"acme, "inc""#));
+        assert_eq!(records[0].requested_at % 1_000_000, 500_000);
+        assert!(records[1].refund);
+        assert_eq!(records[1].merchant, None);
+
+        let lines: Vec<_> = rejected.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [5, 6, 7]);
+        assert_eq!(rejected[0].1, "duplicate in file");
+    }
+
+    #[test]
+    fn test_parse_ndjson() {
+        let input = r#"{"requestedAt":"2025-07-01T12:00:00Z","correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","amount":19.9,"currency":"USD","processor":"default","merchant":null,"type":"payment"}
+{"requestedAt":"2025-07-01T12:00:00Z","correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b4","amount":1,"processor":"default","type":"chargeback"}
+{"amount":1}
+{"requestedAt":"2025-07-01T12:00:00Z","correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b5","amount":1e20,"processor":"default"}
+"#;
+
+        let Parsed { records, rejected } = parse(input.as_bytes(), Format::Ndjson).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].currency.as_str(), "USD");
+        assert_eq!(records[0].amount, 1990);
+        assert_eq!(rejected.len(), 3);
+        assert_eq!(rejected[0].1, r#"invalid type "chargeback""#);
+    }
+}
@@ -1,9 +1,15 @@
 mod api;
+mod bench;
+#[cfg(test)]
+mod consistency;
 mod data;
 mod db;
+mod import;
+mod mock;
+mod shutdown;
 mod worker;
 
-use std::{fs::Permissions, os::unix::fs::PermissionsExt, time::Duration};
+use std::{fs::Permissions, os::unix::fs::PermissionsExt, path::PathBuf, time::Duration};
 
 use anyhow::{Result, anyhow};
 use clap::Parser;
@@ -65,6 +71,12 @@ fn serve(args: Args) {
     let result = match args.mode.as_str() {
         "api" => api::serve(),
         "worker" => worker::serve(),
+        "import" => match &args.file {
+            Some(file) => import::serve(file),
+            None => Err(anyhow!("import needs a file (-f)")),
+        },
+        "bench" => bench::serve(args.file.as_deref()),
+        "mock-processor" => mock::serve(),
         _ => Err(anyhow!("Invalid mode {:?}", args.mode)),
     };
 
@@ -77,11 +89,24 @@ fn get_worker_socket() -> String {
     std::env::var("WORKER_SOCKET").unwrap_or("./worker.sock".to_string())
 }
 
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
 #[derive(Parser)]
 #[command(about = "Rinha 2025")]
 struct Args {
-    #[arg(short = 'm', value_parser = ["api", "worker"], help = "The mode in which the binary will run")]
+    #[arg(short = 'm', value_parser = ["api", "worker", "import", "bench", "mock-processor"], help = "The mode in which the binary will run")]
     mode: String,
+
+    #[arg(
+        short = 'f',
+        help = "Ledger file for import, or payments (JSONL) for bench"
+    )]
+    file: Option<PathBuf>,
 }
 
 fn bind_unix_socket(file: &str) -> Result<UnixListener> {
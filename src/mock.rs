@@ -0,0 +1,464 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{api::http, data, env_or};
+
+/// A payment as the processor recorded it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Record {
+    /// Cents.
+    pub amount: u64,
+    /// The `requestedAt` sent by the client, in micros.
+    pub requested_at: i64,
+    pub refunded: bool,
+}
+
+/// Stand-in for the Rinha payment processor, keeping its own ledger, with
+/// admin controls to slow it down or make it fail.
+pub struct MockProcessor {
+    pub fee: f64,
+    token: Mutex<String>,
+    ledger: Mutex<HashMap<u128, Record>>,
+    /// Added to every payment and refund, in millis.
+    delay: AtomicU64,
+    /// Share of payments answered with `500`, as `f64` bits.
+    failure_rate: AtomicU64,
+    /// Every payment fails and the health check says so.
+    failing: AtomicBool,
+    /// Every `POST /payments`, accepted or not.
+    received: AtomicU64,
+    seed: AtomicU64,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PaymentRequest {
+    correlation_id: String,
+    #[serde(deserialize_with = "data::cents::deserialize")]
+    amount: u64,
+    requested_at: DateTime<Utc>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Configuration {
+    delay: Option<u64>,
+    failure: Option<bool>,
+    rate: Option<f64>,
+    token: Option<String>,
+}
+
+impl MockProcessor {
+    pub fn new(fee: f64) -> Arc<Self> {
+        Arc::new(Self {
+            fee,
+            token: Mutex::new("123".to_string()),
+            ledger: Mutex::default(),
+            delay: AtomicU64::new(0),
+            failure_rate: AtomicU64::new(0f64.to_bits()),
+            failing: AtomicBool::new(false),
+            received: AtomicU64::new(0),
+            seed: AtomicU64::new(Utc::now().timestamp_nanos_opt().unwrap_or(1) as u64 | 1),
+        })
+    }
+
+    pub fn set_delay(&self, delay: Duration) {
+        self.delay
+            .store(delay.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_failure_rate(&self, rate: f64) {
+        self.failure_rate
+            .store(rate.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, correlation_id: &str) -> Option<Record> {
+        let id = data::uuid_to_u128(correlation_id)?;
+
+        self.ledger.lock().unwrap().get(&id).copied()
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.ledger.lock().unwrap().len()
+    }
+
+    #[cfg(test)]
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Payments and their total in cents, within `range` (inclusive).
+    pub fn summary(&self, (from, to): (i64, i64)) -> (u64, u64) {
+        let ledger = self.ledger.lock().unwrap();
+
+        ledger
+            .values()
+            .filter(|r| (from..=to).contains(&r.requested_at))
+            .fold((0, 0), |(count, amount), r| (count + 1, amount + r.amount))
+    }
+
+    /// Binds `addr` and answers every connection until the runtime stops.
+    pub async fn listen(self: &Arc<Self>, addr: &str) -> Result<String> {
+        let listener = TcpListener::bind(addr).await?;
+        let url = format!("http://{}", listener.local_addr()?);
+
+        let mock = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((socket, _)) = listener.accept().await else {
+                    continue;
+                };
+
+                let mock = mock.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = mock.handle(socket).await {
+                        tracing::debug!(?err, "mock_err");
+                    }
+                });
+            }
+        });
+
+        Ok(url)
+    }
+
+    async fn handle(&self, mut socket: TcpStream) -> Result<()> {
+        socket.set_nodelay(true)?;
+
+        let mut buf = vec![0u8; 4096];
+
+        loop {
+            let n = tokio::io::AsyncReadExt::read(&mut socket, &mut buf).await?;
+
+            if n == 0 {
+                return Ok(());
+            }
+
+            let n = http::read_rest(&mut socket, &mut buf, n).await?;
+
+            let mut headers = [httparse::EMPTY_HEADER; http::MAX_HEADERS];
+            let Some(req) = http::parse(&buf[..n], &mut headers)? else {
+                return http::respond_error(&mut socket, "400 Bad Request", "incomplete").await;
+            };
+
+            let body = &buf[req.body_start..n.min(req.body_start + req.content_length())];
+            let (status, body) = self.route(&req, body).await;
+
+            http::respond(&mut socket, status, body.as_bytes()).await?;
+        }
+    }
+
+    async fn route(&self, req: &http::Request<'_, '_>, body: &[u8]) -> (&'static str, String) {
+        const OK: &str = "200 OK";
+        const NOT_FOUND: &str = "404 Not Found";
+
+        let admin = || req.header("X-Rinha-Token") == Some(self.token.lock().unwrap().as_str());
+
+        match (req.method, req.path) {
+            ("POST", "/payments") => self.pay(body).await,
+            ("GET", "/payments/service-health") => {
+                let health = serde_json::json!({
+                    "failing": self.failing.load(Ordering::Relaxed),
+                    "minResponseTime": self.delay.load(Ordering::Relaxed),
+                });
+
+                (OK, health.to_string())
+            }
+            (_, path) if path.starts_with("/admin/") && !admin() => {
+                ("401 Unauthorized", String::new())
+            }
+            ("GET", "/admin/payments-summary") => {
+                let date = |name| {
+                    req.param(name)
+                        .and_then(|d| DateTime::parse_from_rfc3339(&d).ok())
+                        .map(|d| d.timestamp_micros())
+                };
+                let range = (
+                    date("from").unwrap_or(i64::MIN),
+                    date("to").unwrap_or(i64::MAX),
+                );
+
+                let (count, cents) = self.summary(range);
+                let amount = cents as f64 / 100.0;
+
+                let summary = serde_json::json!({
+                    "totalRequests": count,
+                    "totalAmount": amount,
+                    "totalFee": amount * self.fee,
+                    "feePerTransaction": self.fee,
+                });
+
+                (OK, summary.to_string())
+            }
+            ("PUT", path) if path.starts_with("/admin/configurations/") => {
+                match serde_json::from_slice(body) {
+                    Ok(config) => {
+                        self.configure(config);
+                        (OK, String::new())
+                    }
+                    Err(err) => ("400 Bad Request", err.to_string()),
+                }
+            }
+            ("POST", "/admin/purge-payments") => {
+                self.ledger.lock().unwrap().clear();
+                (OK, String::new())
+            }
+            ("POST", path) => match path
+                .strip_prefix("/payments/")
+                .and_then(|p| p.strip_suffix("/refund"))
+            {
+                Some(id) => self.refund(id).await,
+                None => (NOT_FOUND, String::new()),
+            },
+            ("GET", path) if path.starts_with("/payments/") && self.fails() => {
+                ("500 Internal Server Error", String::new())
+            }
+            ("GET", path) => match path.strip_prefix("/payments/").and_then(|id| {
+                let record = self.get(id)?;
+                Some((id, record))
+            }) {
+                Some((id, record)) => {
+                    let payment = serde_json::json!({
+                        "correlationId": id,
+                        "amount": record.amount as f64 / 100.0,
+                        "requestedAt": DateTime::from_timestamp_micros(record.requested_at)
+                            .unwrap_or_default()
+                            .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                    });
+
+                    (OK, payment.to_string())
+                }
+                None => (NOT_FOUND, String::new()),
+            },
+            _ => (NOT_FOUND, String::new()),
+        }
+    }
+
+    fn configure(&self, config: Configuration) {
+        if let Some(delay) = config.delay {
+            self.set_delay(Duration::from_millis(delay));
+        }
+
+        if let Some(failing) = config.failure {
+            self.set_failing(failing);
+        }
+
+        if let Some(rate) = config.rate {
+            self.set_failure_rate(rate);
+        }
+
+        if let Some(token) = config.token {
+            *self.token.lock().unwrap() = token;
+        }
+    }
+
+    /// Records the payment right away and answers after the delay, so a
+    /// client giving up early leaves a payment it does not know about.
+    async fn pay(&self, body: &[u8]) -> (&'static str, String) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+
+        let response = self.process(body);
+        self.wait().await;
+
+        response
+    }
+
+    fn process(&self, body: &[u8]) -> (&'static str, String) {
+        let Ok(req) = serde_json::from_slice::<PaymentRequest>(body) else {
+            return ("400 Bad Request", String::new());
+        };
+
+        let Some(id) = data::uuid_to_u128(&req.correlation_id) else {
+            return ("400 Bad Request", String::new());
+        };
+
+        if req.amount == 0 {
+            return ("422 Unprocessable Entity", String::new());
+        }
+
+        if self.fails() {
+            return ("500 Internal Server Error", String::new());
+        }
+
+        let record = Record {
+            amount: req.amount,
+            requested_at: req.requested_at.timestamp_micros(),
+            refunded: false,
+        };
+
+        let mut ledger = self.ledger.lock().unwrap();
+
+        if ledger.contains_key(&id) {
+            return ("422 Unprocessable Entity", String::new());
+        }
+
+        ledger.insert(id, record);
+
+        let message = serde_json::json!({ "message": "payment processed successfully" });
+
+        ("200 OK", message.to_string())
+    }
+
+    async fn refund(&self, correlation_id: &str) -> (&'static str, String) {
+        self.wait().await;
+
+        if self.fails() {
+            return ("500 Internal Server Error", String::new());
+        }
+
+        let Some(id) = data::uuid_to_u128(correlation_id) else {
+            return ("404 Not Found", String::new());
+        };
+
+        match self.ledger.lock().unwrap().get_mut(&id) {
+            Some(record) if record.refunded => ("409 Conflict", String::new()),
+            Some(record) => {
+                record.refunded = true;
+                ("200 OK", String::new())
+            }
+            None => ("404 Not Found", String::new()),
+        }
+    }
+
+    async fn wait(&self) {
+        let delay = self.delay.load(Ordering::Relaxed);
+
+        if delay > 0 {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+    }
+
+    /// Whether this call fails, by the configured failure rate.
+    fn fails(&self) -> bool {
+        if self.failing.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        let rate = f64::from_bits(self.failure_rate.load(Ordering::Relaxed));
+
+        if rate <= 0.0 {
+            return false;
+        }
+
+        // xorshift
+        let mut x = self.seed.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.seed.store(x, Ordering::Relaxed);
+
+        ((x >> 11) as f64 / (1u64 << 53) as f64) < rate
+    }
+}
+
+/// Runs a mock processor on `MOCK_ADDR` (default `0.0.0.0:8080`).
+#[tokio::main(flavor = "current_thread")]
+pub async fn serve() -> Result<()> {
+    let mock = MockProcessor::new(env_or("MOCK_FEE", 0.05));
+
+    if let Ok(token) = std::env::var("MOCK_TOKEN") {
+        *mock.token.lock().unwrap() = token;
+    }
+
+    mock.set_delay(Duration::from_millis(env_or("MOCK_DELAY_MS", 0)));
+    mock.set_failure_rate(env_or("MOCK_FAILURE_RATE", 0.0));
+
+    let addr = std::env::var("MOCK_ADDR").unwrap_or("0.0.0.0:8080".to_string());
+    let url = mock.listen(&addr).await?;
+
+    tracing::info!("mock processor listening on {url}");
+
+    crate::shutdown::Shutdown::listen().wait().await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Client;
+
+    #[tokio::test]
+    async fn test_mock_processor() {
+        let mock = MockProcessor::new(0.05);
+        let url = mock.listen("127.0.0.1:0").await.unwrap();
+        let client = Client::new();
+
+        let id = "4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3";
+        let payment = serde_json::json!({
+            "correlationId": id,
+            "amount": 19.9,
+            "requestedAt": "2025-07-01T12:00:00.000Z",
+        });
+
+        let pay = || client.post(format!("{url}/payments")).json(&payment).send();
+
+        assert_eq!(pay().await.unwrap().status(), 200);
+        assert_eq!(pay().await.unwrap().status(), 422);
+
+        let found: serde_json::Value = client
+            .get(format!("{url}/payments/{id}"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(found["amount"], 19.9);
+        assert_eq!(found["requestedAt"], "2025-07-01T12:00:00Z");
+
+        let summary = |token: &'static str| {
+            client
+                .get(format!(
+                    "{url}/admin/payments-summary?to=2025-07-01T12:00:00Z"
+                ))
+                .header("X-Rinha-Token", token)
+                .send()
+        };
+        assert_eq!(summary("wrong").await.unwrap().status(), 401);
+
+        let summary: serde_json::Value = summary("123").await.unwrap().json().await.unwrap();
+        assert_eq!(summary["totalRequests"], 1);
+        assert_eq!(summary["totalAmount"], 19.9);
+
+        let failure = client
+            .put(format!("{url}/admin/configurations/failure"))
+            .header("X-Rinha-Token", "123")
+            .json(&serde_json::json!({ "failure": true }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(failure.status(), 200);
+
+        let health: serde_json::Value = client
+            .get(format!("{url}/payments/service-health"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(health["failing"], true);
+        assert_eq!(pay().await.unwrap().status(), 500);
+
+        mock.set_failing(false);
+        mock.set_failure_rate(0.5);
+
+        let failed = (0..200).filter(|_| mock.fails()).count();
+        assert!((50..150).contains(&failed), "{failed} of 200 failed");
+    }
+}
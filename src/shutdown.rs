@@ -0,0 +1,42 @@
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::watch,
+};
+
+/// Resolves once SIGTERM or SIGINT is received, shared by every task that
+/// needs to wind down.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    pub fn listen() -> Self {
+        let (tx, shutdown) = Self::channel();
+
+        tokio::spawn(async move {
+            let mut term = signal(SignalKind::terminate()).expect("register SIGTERM");
+            let mut int = signal(SignalKind::interrupt()).expect("register SIGINT");
+
+            tokio::select! {
+                _ = term.recv() => tracing::info!("received SIGTERM"),
+                _ = int.recv() => tracing::info!("received SIGINT"),
+            }
+
+            tx.send(true).ok();
+        });
+
+        shutdown
+    }
+
+    /// A shutdown triggered by sending `true` (or dropping the sender) instead
+    /// of a signal, for running the modes in-process.
+    pub fn channel() -> (watch::Sender<bool>, Self) {
+        let (tx, rx) = watch::channel(false);
+
+        (tx, Self(rx))
+    }
+
+    pub async fn wait(&mut self) {
+        // the sender only goes away after sending, so an error is a shutdown too
+        _ = self.0.wait_for(|triggered| *triggered).await;
+    }
+}
@@ -0,0 +1,212 @@
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use anyhow::Result;
+use tokio::net::UnixStream;
+
+use crate::{
+    api::export::Format,
+    data::{self, Payment},
+    db,
+    worker::{Context, export},
+};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    pub queue_depth: usize,
+    pub inflight: usize,
+    pub paused: bool,
+    pub forced_processor: Option<String>,
+    pub processors: Vec<ProcessorStats>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessorStats {
+    pub name: String,
+    pub fee: f64,
+    pub weight: u32,
+    pub limit: usize,
+    pub inflight: usize,
+    pub p50: u64,
+    pub p99: u64,
+    pub ewma: u64,
+    pub failure_rate: f64,
+}
+
+pub(super) async fn stats(socket: &mut UnixStream, ctx: &Context) -> Result<()> {
+    let stats = Stats {
+        queue_depth: ctx.tx.len(),
+        inflight: ctx.manager.inflight(),
+        paused: ctx.manager.is_paused(),
+        forced_processor: ctx.manager.forced(),
+        processors: ctx.manager.stats(),
+    };
+
+    data::send_large(stats, socket).await
+}
+
+/// Replies whether `processor` exists; `None` goes back to the routing strategy.
+pub(super) async fn force_route(
+    socket: &mut UnixStream,
+    ctx: &Context,
+    processor: Option<String>,
+) -> Result<()> {
+    let found = ctx.manager.force(processor.as_deref());
+
+    match (found, &processor) {
+        (true, Some(name)) => tracing::info!("routing forced to {name}"),
+        (true, None) => tracing::info!("routing back to strategy"),
+        (false, _) => tracing::warn!(?processor, "unknown processor"),
+    }
+
+    data::send_large(found, socket).await
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PurgeRequest {
+    /// Inclusive `requested_at` range in micros, everything on `None`.
+    pub range: Option<(i64, i64)>,
+    pub snapshot: bool,
+    pub dry_run: bool,
+    /// `X-Admin-User`, as sent by the client and not verified.
+    pub actor: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PurgeResult {
+    pub deleted: usize,
+    pub dry_run: bool,
+    pub snapshot: Option<String>,
+}
+
+pub(super) async fn purge(socket: &mut UnixStream, ctx: &Context, req: PurgeRequest) -> Result<()> {
+    let result = execute_purge(ctx, &req).await;
+
+    match &result {
+        Ok(result) => tracing::info!(
+            target: "audit",
+            self_reported_actor = req.actor,
+            range = ?req.range,
+            dry_run = req.dry_run,
+            deleted = result.deleted,
+            snapshot = ?result.snapshot,
+            "audit: purge"
+        ),
+        Err(err) => tracing::error!(
+            target: "audit",
+            self_reported_actor = req.actor,
+            range = ?req.range,
+            dry_run = req.dry_run,
+            ?err,
+            "audit: purge failed"
+        ),
+    }
+
+    data::send_large(result.map_err(|err| err.to_string()), socket).await
+}
+
+async fn execute_purge(ctx: &Context, req: &PurgeRequest) -> Result<PurgeResult> {
+    if req.dry_run {
+        return Ok(PurgeResult {
+            deleted: ctx.store.count(req.range).await,
+            dry_run: true,
+            snapshot: None,
+        });
+    }
+
+    // taken under a single lock, so the snapshot holds exactly what was deleted
+    let purged = ctx.store.purge(req.range).await;
+    let deleted = purged.len();
+
+    let snapshot = match req.snapshot {
+        true => {
+            let dir = std::env::var("PURGE_SNAPSHOT_DIR").unwrap_or("./snapshots".to_string());
+            let store = ctx.store.clone();
+
+            let written = tokio::task::spawn_blocking(move || {
+                snapshot(&dir, &store, &purged).map_err(|err| (err, purged))
+            })
+            .await?;
+
+            match written {
+                Ok(path) => Some(path),
+                // put back, so a failed snapshot loses nothing
+                Err((err, purged)) => {
+                    ctx.store.insert_bulk(purged).await;
+                    return Err(err);
+                }
+            }
+        }
+        false => None,
+    };
+
+    Ok(PurgeResult {
+        deleted,
+        dry_run: false,
+        snapshot,
+    })
+}
+
+/// Writes `payments` as export NDJSON under `dir`.
+fn snapshot(dir: &str, store: &db::Store, payments: &[Payment]) -> Result<String> {
+    std::fs::create_dir_all(dir)?;
+
+    let name = format!("purge-{}.ndjson", chrono::Utc::now().timestamp_micros());
+    let path = PathBuf::from(dir).join(name);
+
+    let mut writer = BufWriter::new(File::create(&path)?);
+
+    for payment in payments {
+        export::write_record(&mut writer, store, Format::Ndjson, payment)?;
+    }
+
+    writer.into_inner()?.sync_all()?;
+
+    Ok(path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Currency;
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let store = db::Store::new(vec!["default".to_string()]);
+
+        let payment = Payment {
+            correlation_id: data::uuid_to_u128("4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3").unwrap(),
+            amount: 1990,
+            requested_at: 10,
+            processor_id: 0,
+            merchant_id: db::NO_MERCHANT,
+            currency: Currency::default(),
+            refund: false,
+        };
+        let refund = Payment {
+            requested_at: 20,
+            refund: true,
+            ..payment
+        };
+
+        let dir = std::env::temp_dir().join(format!("rinha-{}-snapshot", std::process::id()));
+        let path = snapshot(&dir.display().to_string(), &store, &[payment, refund]).unwrap();
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(
+            lines[0]["correlationId"],
+            "4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3"
+        );
+        assert_eq!(lines[0]["type"], "payment");
+        assert_eq!(lines[1]["type"], "refund");
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,172 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+const ORD: Ordering = Ordering::Relaxed;
+
+/// Lock-free latency/failure estimator for one payment processor.
+///
+/// Keeps an EWMA of the latency and of the failure rate, plus a ring of the
+/// last `WINDOW` latency samples whose p50/p99 are recomputed every
+/// `REFRESH_EVERY` samples, so readers on the hot path only load atomics.
+pub struct Estimator {
+    alpha: f64,
+    ewma: AtomicU64,
+    failure_rate: AtomicU64,
+    samples: Box<[AtomicU64]>,
+    cursor: AtomicUsize,
+    p50: AtomicU64,
+    p99: AtomicU64,
+}
+
+const WINDOW: usize = 256;
+const REFRESH_EVERY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    pub ewma: u64,
+    pub p50: u64,
+    pub p99: u64,
+    pub failure_rate: f64,
+}
+
+impl Estimator {
+    pub fn new(alpha: f64) -> Self {
+        Self {
+            alpha,
+            ewma: AtomicU64::new(0f64.to_bits()),
+            failure_rate: AtomicU64::new(0f64.to_bits()),
+            samples: (0..WINDOW).map(|_| AtomicU64::new(0)).collect(),
+            cursor: AtomicUsize::new(0),
+            p50: AtomicU64::new(0),
+            p99: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a call that got an answer (or timed out) after `micros`.
+    pub fn record(&self, micros: u64, ok: bool) {
+        self.smooth(&self.ewma, micros as f64);
+        self.record_outcome(ok);
+
+        let n = self.cursor.fetch_add(1, ORD);
+        self.samples[n % WINDOW].store(micros, ORD);
+
+        if n % REFRESH_EVERY == REFRESH_EVERY - 1 {
+            self.refresh(n + 1);
+        }
+    }
+
+    /// Records a failed call without a meaningful latency.
+    pub fn record_outcome(&self, ok: bool) {
+        self.smooth(&self.failure_rate, if ok { 0.0 } else { 1.0 });
+    }
+
+    pub fn estimate(&self) -> Estimate {
+        Estimate {
+            ewma: f64::from_bits(self.ewma.load(ORD)) as u64,
+            p50: self.p50.load(ORD),
+            p99: self.p99.load(ORD),
+            failure_rate: f64::from_bits(self.failure_rate.load(ORD)),
+        }
+    }
+
+    fn smooth(&self, value: &AtomicU64, sample: f64) {
+        let alpha = self.alpha;
+
+        _ = value.fetch_update(ORD, ORD, |bits| {
+            let current = f64::from_bits(bits);
+            Some((current + alpha * (sample - current)).to_bits())
+        });
+    }
+
+    fn refresh(&self, recorded: usize) {
+        let mut window: Vec<_> = self.samples[..recorded.min(WINDOW)]
+            .iter()
+            .map(|s| s.load(ORD))
+            .collect();
+
+        window.sort_unstable();
+
+        let percentile = |p: f64| window[((window.len() - 1) as f64 * p).round() as usize];
+
+        self.p50.store(percentile(0.50), ORD);
+        self.p99.store(percentile(0.99), ORD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let estimator = Estimator::new(0.1);
+
+        for i in 0..WINDOW as u64 {
+            estimator.record(1_000 + i, true);
+        }
+
+        let estimate = estimator.estimate();
+        assert_eq!(estimate.p50, 1_128);
+        assert_eq!(estimate.p99, 1_252);
+        assert_eq!(estimate.failure_rate, 0.0);
+    }
+
+    #[test]
+    fn test_single_outlier() {
+        let estimator = Estimator::new(0.1);
+
+        for i in 0..WINDOW {
+            let latency = if i == 100 { 5_000_000 } else { 2_000 };
+            estimator.record(latency, true);
+        }
+
+        let estimate = estimator.estimate();
+        assert_eq!(estimate.p50, 2_000);
+        assert_eq!(estimate.p99, 2_000);
+        assert!(estimate.ewma < 3_000);
+    }
+
+    #[test]
+    fn test_failure_rate() {
+        let estimator = Estimator::new(0.2);
+
+        for _ in 0..50 {
+            estimator.record_outcome(false);
+        }
+        assert!(estimator.estimate().failure_rate > 0.99);
+
+        for _ in 0..50 {
+            estimator.record(1_000, true);
+        }
+        assert!(estimator.estimate().failure_rate < 0.01);
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let timeouts = Estimator::new(0.2);
+        let errors = Estimator::new(0.2);
+
+        for i in 0..WINDOW as u64 {
+            let failed = i % 2 == 0;
+
+            // a timeout keeps its (slow) latency, an error only counts
+            match failed {
+                true => timeouts.record(2_000_000, false),
+                false => timeouts.record(1_000, true),
+            }
+
+            match failed {
+                true => errors.record_outcome(false),
+                false => errors.record(1_000, true),
+            }
+        }
+
+        let (timeouts, errors) = (timeouts.estimate(), errors.estimate());
+
+        assert!((timeouts.failure_rate - errors.failure_rate).abs() < 1e-9);
+        assert!(timeouts.failure_rate > 0.3);
+
+        assert_eq!(errors.p99, 1_000);
+        assert_eq!(timeouts.p99, 2_000_000);
+        assert!(timeouts.ewma > errors.ewma);
+    }
+}
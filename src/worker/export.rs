@@ -0,0 +1,179 @@
+use std::{borrow::Cow, io::Write};
+
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    api::export::{Export, Format},
+    data::{self, Payment},
+    db,
+};
+
+const CSV_HEADER: &[u8] = b"requestedAt,correlationId,amount,currency,processor,merchant,type\n";
+
+/// Sends the payments of `export` as formatted chunks of about `chunk_size`
+/// records, followed by `None`.
+pub async fn export<S: AsyncWriteExt + Unpin>(
+    socket: &mut S,
+    store: &db::Store,
+    export: Export,
+    chunk_size: usize,
+) -> Result<()> {
+    let Export { query, format } = export;
+    let (mut from, to) = query.range;
+
+    let mut chunk = Vec::new();
+
+    if format == Format::Csv {
+        chunk.extend_from_slice(CSV_HEADER);
+    }
+
+    loop {
+        let page = store.page((from, to), &query.scope, chunk_size).await;
+
+        for payment in &page {
+            write_record(&mut chunk, store, format, payment)?;
+        }
+
+        if !chunk.is_empty() {
+            data::send_large(Some(std::mem::take(&mut chunk)), socket).await?;
+        }
+
+        match page.last() {
+            Some(last) if page.len() >= chunk_size && last.requested_at < to => {
+                from = last.requested_at + 1;
+            }
+            _ => break,
+        }
+    }
+
+    data::send_large(None::<Vec<u8>>, socket).await
+}
+
+/// One payment as a line of the export, also used by purge snapshots.
+pub(super) fn write_record(
+    writer: &mut impl Write,
+    store: &db::Store,
+    format: Format,
+    payment: &Payment,
+) -> Result<()> {
+    let requested_at = DateTime::from_timestamp_micros(payment.requested_at)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::AutoSi, true);
+
+    let correlation_id = data::u128_to_uuid(payment.correlation_id);
+    let processor = store.processor_name(payment.processor_id);
+    let merchant = store.merchant_name(payment.merchant_id);
+
+    let kind = match payment.refund {
+        true => "refund",
+        false => "payment",
+    };
+
+    match format {
+        Format::Csv => writeln!(
+            writer,
+            "{requested_at},{correlation_id},{}.{:02},{},{},{},{kind}",
+            payment.amount / 100,
+            payment.amount % 100,
+            payment.currency,
+            csv_field(processor),
+            csv_field(merchant.as_deref().unwrap_or_default()),
+        )?,
+        Format::Ndjson => {
+            let record = serde_json::json!({
+                "requestedAt": requested_at,
+                "correlationId": correlation_id,
+                "amount": data::cents::to_decimal(payment.amount),
+                "currency": payment.currency.as_str(),
+                "processor": processor,
+                "merchant": merchant,
+                "type": kind,
+            });
+
+            serde_json::to_writer(&mut *writer, &record)?;
+            writeln!(writer)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn csv_field(value: &str) -> Cow<'_, str> {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => Cow::Owned(format!("\"{}\"", value.replace('"', "\"\""))),
+        false => Cow::Borrowed(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::summary::{Query, Scope},
+        data::Currency,
+    };
+
+    async fn collect(store: &db::Store, format: Format, scope: Scope) -> Vec<String> {
+        let (mut worker, mut api) = tokio::io::duplex(1 << 16);
+
+        let query = Export {
+            query: Query {
+                range: (20, i64::MAX),
+                scope,
+            },
+            format,
+        };
+
+        export(&mut worker, store, query, 2).await.unwrap();
+
+        let mut chunks = Vec::new();
+        while let Some(chunk) = data::recv::<Option<Vec<u8>>, _>(&mut api).await.unwrap() {
+            chunks.push(String::from_utf8(chunk).unwrap());
+        }
+
+        chunks
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let store = db::Store::new(vec!["default".to_string(), "fallback".to_string()]);
+        let acme = store.merchant_id(Some("acme, inc"));
+
+        for (i, requested_at) in [10, 20, 30, 30, 30, 40].into_iter().enumerate() {
+            let payment = Payment {
+                correlation_id: i as u128,
+                amount: 1990,
+                requested_at,
+                processor_id: (i % 2) as u8,
+                merchant_id: acme,
+                currency: Currency::default(),
+                refund: i == 5,
+            };
+
+            store.insert(payment).await;
+        }
+
+        let chunks = collect(&store, Format::Csv, Scope::All).await;
+
+        // the payments sharing a timestamp stay in the same chunk
+        let lines: Vec<Vec<&str>> = chunks.iter().map(|c| c.lines().collect()).collect();
+        assert_eq!(lines.iter().map(Vec::len).collect::<Vec<_>>(), [5, 1]);
+        assert_eq!(lines[0][0].as_bytes(), CSV_HEADER.trim_ascii_end());
+        assert_eq!(
+            lines[0][1],
+            r#"1970-01-01T00:00:00.000020Z,00000000-0000-0000-0000-000000000001,19.90,BRL,fallback,"acme, inc",payment"#
+        );
+        assert!(lines[1][0].ends_with(",refund"));
+
+        let chunks = collect(&store, Format::Ndjson, Scope::Merchant(None)).await;
+        assert!(chunks.is_empty());
+
+        let chunks = collect(&store, Format::Ndjson, Scope::All).await;
+        let record: serde_json::Value =
+            serde_json::from_str(chunks[0].lines().next().unwrap()).unwrap();
+        assert_eq!(record["amount"], 19.9);
+        assert_eq!(record["merchant"], "acme, inc");
+    }
+}
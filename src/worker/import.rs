@@ -0,0 +1,226 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use tokio::net::UnixStream;
+
+use crate::{
+    api::summary::Scope,
+    data::{self, Currency, Payment},
+    db,
+};
+
+/// A processed payment (or refund) from a ledger file, checked by the
+/// importer but not yet against the worker's state.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ImportRecord {
+    pub correlation_id: u128,
+    /// Cents.
+    pub amount: u64,
+    pub requested_at: i64,
+    pub currency: Currency,
+    pub processor: String,
+    pub merchant: Option<String>,
+    pub refund: bool,
+}
+
+#[derive(Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ImportResult {
+    pub inserted: usize,
+    /// Correlation id and reason of each record left out.
+    pub rejected: Vec<(u128, String)>,
+}
+
+/// Stores the records this worker accepts in one go.
+pub async fn import(
+    socket: &mut UnixStream,
+    store: &db::Store,
+    records: Vec<ImportRecord>,
+) -> Result<()> {
+    let result = insert(store, records).await;
+
+    tracing::info!(
+        inserted = result.inserted,
+        rejected = result.rejected.len(),
+        "imported payments"
+    );
+
+    data::send_large(result, socket).await
+}
+
+async fn insert(store: &db::Store, records: Vec<ImportRecord>) -> ImportResult {
+    let mut result = ImportResult::default();
+    let mut payments = Vec::with_capacity(records.len());
+    let mut seen = HashMap::new();
+    let mut refunded = HashSet::new();
+
+    for record in records {
+        let Some(processor_id) = store
+            .processors()
+            .iter()
+            .position(|p| *p == record.processor)
+        else {
+            let reason = format!("unknown processor {:?}", record.processor);
+            result.rejected.push((record.correlation_id, reason));
+            continue;
+        };
+
+        let id = record.correlation_id;
+        let original = seen
+            .get(&id)
+            .copied()
+            .or_else(|| store.find(id, &Scope::All).map(|(payment, _)| payment));
+
+        // like a live refund, only within the payment's merchant and through
+        // its processor
+        let rejection = match (record.refund, original) {
+            (false, Some(_)) => Some("already stored"),
+            (true, None) => Some("refund of an unknown payment"),
+            (true, Some(p)) if store.merchant_name(p.merchant_id) != record.merchant => {
+                Some("refund of an unknown payment")
+            }
+            (true, Some(p)) if p.processor_id as usize != processor_id => {
+                Some("refund through another processor")
+            }
+            (true, _) if !refunded.insert(id) || is_refunded(store, id) => Some("already refunded"),
+            _ => None,
+        };
+
+        if let Some(reason) = rejection {
+            result.rejected.push((id, reason.to_string()));
+            continue;
+        }
+
+        let payment = Payment {
+            correlation_id: record.correlation_id,
+            amount: record.amount,
+            requested_at: record.requested_at,
+            processor_id: processor_id as u8,
+            merchant_id: store.merchant_id(record.merchant.as_deref()),
+            currency: record.currency,
+            refund: record.refund,
+        };
+
+        if !record.refund {
+            seen.insert(id, payment);
+        }
+
+        payments.push(payment);
+    }
+
+    result.inserted = payments.len();
+
+    store.insert_bulk(payments).await;
+
+    result
+}
+
+fn is_refunded(store: &db::Store, id: u128) -> bool {
+    store
+        .find(id, &Scope::All)
+        .is_some_and(|(_, refunded)| refunded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(correlation_id: u128, requested_at: i64, processor: &str) -> ImportRecord {
+        ImportRecord {
+            correlation_id,
+            amount: 1000,
+            requested_at,
+            currency: Currency::default(),
+            processor: processor.to_string(),
+            merchant: Some("acme".to_string()),
+            refund: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert() {
+        let store = db::Store::new(vec!["default".to_string(), "fallback".to_string()]);
+
+        let existing = Payment {
+            correlation_id: 1,
+            amount: 1000,
+            requested_at: 50,
+            processor_id: 0,
+            merchant_id: db::NO_MERCHANT,
+            currency: Currency::default(),
+            refund: false,
+        };
+        store.insert(existing).await;
+
+        let result = insert(
+            &store,
+            vec![
+                record(1, 10, "default"),
+                record(2, 20, "fallback"),
+                record(3, 30, "backup"),
+                ImportRecord {
+                    refund: true,
+                    ..record(2, 60, "fallback")
+                },
+                // the same payment twice in a batch, and refunds without one
+                record(4, 70, "default"),
+                record(4, 71, "default"),
+                ImportRecord {
+                    refund: true,
+                    ..record(5, 80, "default")
+                },
+                ImportRecord {
+                    refund: true,
+                    ..record(2, 90, "fallback")
+                },
+            ],
+        )
+        .await;
+
+        assert_eq!(result.inserted, 3);
+        let rejected: Vec<_> = result.rejected.iter().map(|(id, _)| *id).collect();
+        assert_eq!(rejected, [1, 3, 4, 5, 2]);
+
+        // historic payments are queried like any other
+        let summary = store
+            .get((0, 40), &Scope::Merchant(Some("acme".to_string())))
+            .await;
+        assert_eq!(summary[0].get("fallback").unwrap().count, 1);
+
+        let (_, refunded) = store.find(2, &Scope::All).unwrap();
+        assert!(refunded);
+    }
+
+    #[tokio::test]
+    async fn test_refund_mismatch() {
+        let store = db::Store::new(vec!["default".to_string(), "fallback".to_string()]);
+
+        let refund = |merchant: Option<&str>, processor| ImportRecord {
+            refund: true,
+            merchant: merchant.map(str::to_string),
+            ..record(1, 20, processor)
+        };
+
+        let result = insert(
+            &store,
+            vec![
+                record(1, 10, "default"),
+                refund(Some("other"), "default"),
+                refund(None, "default"),
+                refund(Some("acme"), "fallback"),
+                refund(Some("acme"), "default"),
+            ],
+        )
+        .await;
+
+        assert_eq!(result.inserted, 2);
+        let reasons: Vec<_> = result.rejected.iter().map(|(_, r)| r.as_str()).collect();
+        assert_eq!(
+            reasons,
+            [
+                "refund of an unknown payment",
+                "refund of an unknown payment",
+                "refund through another processor",
+            ]
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+use crate::env_or;
+
+/// AIMD concurrency limit for the calls to one payment processor: grows by
+/// one every `limit` fast successes and shrinks by `backoff` whenever a call
+/// fails or is slower than `target`.
+pub struct Limiter {
+    limit: AtomicU64,
+    inflight: AtomicUsize,
+    notify: Notify,
+    config: LimiterConfig,
+}
+
+#[derive(Clone, Copy)]
+pub struct LimiterConfig {
+    pub initial: usize,
+    pub min: usize,
+    pub max: usize,
+    pub target: Duration,
+    pub backoff: f64,
+}
+
+impl LimiterConfig {
+    pub fn from_env() -> Self {
+        Self {
+            initial: env_or("LIMIT_INITIAL", 16),
+            min: env_or("LIMIT_MIN", 1),
+            max: env_or("LIMIT_MAX", 256),
+            target: Duration::from_millis(env_or("LIMIT_TARGET_LATENCY_MS", 150)),
+            backoff: env_or("LIMIT_BACKOFF", 0.9),
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(config: LimiterConfig) -> Arc<Self> {
+        let initial = config.initial.clamp(config.min, config.max) as f64;
+
+        Arc::new(Self {
+            limit: AtomicU64::new(initial.to_bits()),
+            inflight: AtomicUsize::new(0),
+            notify: Notify::new(),
+            config,
+        })
+    }
+
+    pub fn limit(&self) -> usize {
+        f64::from_bits(self.limit.load(Ordering::Relaxed)) as usize
+    }
+
+    pub fn inflight(&self) -> usize {
+        self.inflight.load(Ordering::Relaxed)
+    }
+
+    pub async fn acquire(self: &Arc<Self>) -> Permit {
+        loop {
+            let notified = self.notify.notified();
+
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+
+            notified.await;
+        }
+    }
+
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let limit = self.limit();
+
+        self.inflight
+            .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |n| {
+                (n < limit).then_some(n + 1)
+            })
+            .ok()?;
+
+        Some(Permit {
+            limiter: self.clone(),
+        })
+    }
+
+    pub fn record(&self, latency: Duration, ok: bool) {
+        let config = &self.config;
+
+        let result = self
+            .limit
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let limit = f64::from_bits(bits);
+
+                let new = if ok && latency <= config.target {
+                    limit + 1.0 / limit
+                } else {
+                    limit * config.backoff
+                };
+
+                Some(new.clamp(config.min as f64, config.max as f64).to_bits())
+            });
+
+        if let Ok(old) = result
+            && self.limit() > f64::from_bits(old) as usize
+        {
+            self.notify.notify_waiters();
+        }
+    }
+}
+
+pub struct Permit {
+    limiter: Arc<Limiter>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.inflight.fetch_sub(1, Ordering::AcqRel);
+        self.limiter.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: LimiterConfig = LimiterConfig {
+        initial: 4,
+        min: 1,
+        max: 8,
+        target: Duration::from_millis(100),
+        backoff: 0.5,
+    };
+
+    #[test]
+    fn test_aimd() {
+        let limiter = Limiter::new(CONFIG);
+
+        for _ in 0..5 {
+            limiter.record(Duration::from_millis(10), true);
+        }
+        assert_eq!(limiter.limit(), 5);
+
+        limiter.record(Duration::from_millis(500), true);
+        assert_eq!(limiter.limit(), 2);
+
+        for _ in 0..10 {
+            limiter.record(Duration::from_millis(10), false);
+        }
+        assert_eq!(limiter.limit(), 1);
+
+        for _ in 0..1000 {
+            limiter.record(Duration::from_millis(10), true);
+        }
+        assert_eq!(limiter.limit(), 8);
+    }
+
+    #[test]
+    fn test_permits() {
+        let limiter = Limiter::new(LimiterConfig {
+            initial: 2,
+            ..CONFIG
+        });
+
+        let a = limiter.try_acquire().expect("first permit");
+        let _b = limiter.try_acquire().expect("second permit");
+        assert!(limiter.try_acquire().is_none());
+        assert_eq!(limiter.inflight(), 2);
+
+        drop(a);
+        assert!(limiter.try_acquire().is_some());
+    }
+}
@@ -1,107 +1,389 @@
-mod pp_client;
-mod summary;
-
-use std::sync::Arc;
+pub mod admin;
+mod estimator;
+mod export;
+pub mod import;
+mod limiter;
+mod pending;
+pub mod pp_client;
+mod ratelimit;
+pub mod refund;
+mod routing;
+mod scheduler;
+pub mod status;
+pub mod summary;
+#[cfg(test)]
+mod testing;
+mod webhook;
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use reqwest::Client;
 use tokio::net::UnixStream;
 
 use crate::{
-    api, bind_unix_socket, data, db, get_worker_socket, worker::pp_client::PaymentsManager,
+    api, bind_unix_socket, data, db, env_or, get_worker_socket,
+    shutdown::Shutdown,
+    worker::{
+        pending::PendingQueue,
+        pp_client::{PaymentsManager, ProcessorConfig},
+        ratelimit::RateLimiter,
+        scheduler::Scheduler,
+        webhook::{Event, WebhookConfig, Webhooks},
+    },
 };
 
 #[tokio::main(flavor = "current_thread")]
 pub async fn serve() -> Result<()> {
     tracing::info!("starting worker");
 
-    let store = db::Store::new();
+    let processors = ProcessorConfig::from_env()?;
 
-    let req_tx = start_http_workers(store.clone());
+    let store = db::Store::new(processors.iter().map(|p| p.name.clone()).collect());
 
-    uds_listen(req_tx, store).await
+    run(&processors, store, &get_worker_socket(), Shutdown::listen()).await
 }
 
-fn start_http_workers(store: db::Store) -> Sender {
-    let (tx, rx) = flume::unbounded();
+/// Serves the APIs on `socket`, storing what `processors` accept in `store`,
+/// until `shutdown`, then drains the queued payments.
+pub async fn run(
+    processors: &[ProcessorConfig],
+    store: db::Store,
+    socket: &str,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let strategy = routing::from_env()?;
+
+    let client = http_client()?;
+
+    let rate_limiter = RateLimiter::from_env()?.map(Arc::new);
+
+    let (pending, replayed) = match std::env::var("PENDING_QUEUE_FILE") {
+        Ok(path) => PendingQueue::open(path)?,
+        Err(_) => (PendingQueue::disabled(), Vec::new()),
+    };
+    let pending = Arc::new(pending);
+
+    let webhooks = match WebhookConfig::from_env()? {
+        Some(config) => Webhooks::start(config)?,
+        None => Webhooks::disabled(),
+    };
+
+    let scheduler = Arc::new(Scheduler::new());
+
+    let (req_tx, manager) = start_http_workers(
+        processors,
+        strategy,
+        store.clone(),
+        pending.clone(),
+        webhooks,
+        scheduler.clone(),
+        &client,
+    );
+
+    tokio::spawn({
+        let scheduler = scheduler.clone();
+        let tx = req_tx.clone();
+        async move { scheduler.run(tx).await }
+    });
+
+    tracing::info!("replaying {} pending payments", replayed.len());
+    for req in replayed {
+        submit(&req_tx, &scheduler, req).await?;
+    }
 
-    let http_workers = std::env::var("HTTP_WORKERS")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(16);
+    let ctx = Context {
+        tx: req_tx.clone(),
+        store,
+        pending: pending.clone(),
+        manager: manager.clone(),
+        rate_limiter,
+        scheduler: scheduler.clone(),
+    };
 
-    let default = std::env::var("PROCESSOR_DEFAULT")
-        .unwrap_or("http://payment-processor-default:8080".to_string());
+    uds_listen(socket, ctx, shutdown).await?;
 
-    let fallback = std::env::var("PROCESSOR_FALLBACK")
-        .unwrap_or("http://payment-processor-fallback:8080".to_string());
+    std::fs::remove_file(socket).ok();
 
-    let micros_cutout = std::env::var("PROCESSOR_CUTOUT")
-        .ok()
-        .and_then(|c| c.parse().ok())
-        .unwrap_or(100_000); //100ms
+    let deadline = Duration::from_millis(env_or("SHUTDOWN_DEADLINE_MS", 5_000));
 
-    let reset_timeout = std::env::var("RESET_TIMEOUT")
-        .ok()
-        .and_then(|c| c.parse().ok())
-        .unwrap_or(6);
+    drain(&req_tx, &manager, deadline).await;
 
-    let manager = PaymentsManager::new(&default, &fallback, micros_cutout, store, &Client::new());
+    match (scheduler.len(), pending.is_durable()) {
+        (0, _) => {}
+        (scheduled, true) => {
+            tracing::info!("{scheduled} scheduled payments kept for the next start")
+        }
+        (scheduled, false) => {
+            tracing::warn!("{scheduled} scheduled payments dropped, PENDING_QUEUE_FILE is unset")
+        }
+    }
 
-    manager.start(reset_timeout);
+    pending.flush().await
+}
 
-    tracing::info!("starting {http_workers} http_workers");
-    for _ in 0..http_workers {
-        let worker = start_http_worker(manager.clone(), tx.clone(), rx.clone());
-        tokio::spawn(async {
-            if let Err(err) = worker.await {
-                tracing::error!(?err, "http_worker_err")
+/// Waits until every queued and in flight payment reached a processor, or
+/// `deadline` passes, returning how many did not.
+async fn drain(tx: &Sender, manager: &PaymentsManager, deadline: Duration) -> usize {
+    tracing::info!(queued = tx.len(), "draining payments");
+
+    let start = Instant::now();
+
+    while (!tx.is_empty() || manager.inflight() > 0) && start.elapsed() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let left = tx.len() + manager.inflight();
+
+    match left {
+        0 => tracing::info!("all payments processed"),
+        _ => tracing::warn!("{left} payments left unprocessed"),
+    }
+
+    left
+}
+
+fn start_http_workers(
+    processors: &[ProcessorConfig],
+    strategy: Box<dyn routing::RoutingStrategy>,
+    store: db::Store,
+    pending: Arc<PendingQueue>,
+    webhooks: Arc<Webhooks>,
+    scheduler: Arc<Scheduler>,
+    client: &Client,
+) -> (Sender, Arc<PaymentsManager>) {
+    let (tx, rx) = flume::unbounded();
+
+    let health_interval = env_or("HEALTH_INTERVAL", 5);
+    let alpha = env_or("ESTIMATOR_ALPHA", 0.1);
+
+    let limits = limiter::LimiterConfig::from_env();
+
+    let manager = PaymentsManager::new(
+        processors,
+        strategy,
+        limits,
+        alpha,
+        store,
+        pending.clone(),
+        client,
+    );
+
+    manager.start(health_interval);
+
+    tracing::info!("starting dispatcher with {} initial limit", limits.initial);
+    let dispatcher = dispatch(
+        manager.clone(),
+        pending,
+        webhooks,
+        RetryPolicy::from_env(),
+        scheduler,
+        rx,
+    );
+    tokio::spawn(async {
+        if let Err(err) = dispatcher.await {
+            tracing::error!(?err, "dispatcher_err")
+        }
+    });
+
+    (tx, manager)
+}
+
+fn http_client() -> Result<Client> {
+    let millis = |name, default| Duration::from_millis(env_or(name, default));
+
+    let client = Client::builder()
+        .connect_timeout(millis("PROCESSOR_CONNECT_TIMEOUT_MS", 500))
+        .timeout(millis("PROCESSOR_TIMEOUT_MS", 2_000))
+        .pool_idle_timeout(millis("PROCESSOR_POOL_IDLE_MS", 90_000))
+        .pool_max_idle_per_host(env_or("PROCESSOR_POOL_MAX_IDLE", 32))
+        .tcp_keepalive(millis("PROCESSOR_TCP_KEEPALIVE_MS", 30_000))
+        .tcp_nodelay(true)
+        .build()?;
+
+    Ok(client)
+}
+
+/// Queues `payments` once the journal has them, replying whether it did; the
+/// API only acknowledges them after that.
+async fn accept(
+    stream: &mut UnixStream,
+    ctx: &Context,
+    payments: Vec<api::payment::Request>,
+) -> Result<()> {
+    let journaled = ctx.pending.enqueue(&payments).await;
+
+    match &journaled {
+        Ok(()) => {
+            for req in payments {
+                submit(&ctx.tx, &ctx.scheduler, req).await?;
             }
-        });
+        }
+        Err(err) => tracing::error!(?err, payments = payments.len(), "pending_err"),
     }
 
-    tx
+    data::send_large(journaled.map_err(|err| err.to_string()), stream).await
 }
 
-async fn start_http_worker(manager: Arc<PaymentsManager>, tx: Sender, rx: Receiver) -> Result<()> {
+/// Queues `req` for a processor, or keeps it in the scheduler while it is
+/// not due.
+async fn submit(tx: &Sender, scheduler: &Scheduler, req: api::payment::Request) -> Result<()> {
+    match req.scheduled_at {
+        Some(at) if at > chrono::Utc::now().timestamp_micros() => scheduler.schedule(req),
+        _ => tx.send_async(req).await?,
+    }
+
+    Ok(())
+}
+
+/// How failed payments are retried.
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    /// Attempts before a payment is dropped, `0` retries forever.
+    max_attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    fn from_env() -> Self {
+        Self {
+            max_attempts: env_or("MAX_ATTEMPTS", 0),
+            backoff: Duration::from_millis(env_or("RETRY_BACKOFF_MS", 50)),
+            max_backoff: Duration::from_millis(env_or("RETRY_MAX_BACKOFF_MS", 5_000)),
+        }
+    }
+
+    /// Wait before the next attempt, doubling with each failed one.
+    fn delay(&self, attempts: u32) -> Duration {
+        let factor = 1 << attempts.saturating_sub(1).min(16);
+
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// Hands each queued payment to the processor picked by the manager as soon
+/// as that processor's concurrency limit allows it. A failed payment waits in
+/// the scheduler for its retry, and one the processor refuses is dropped.
+async fn dispatch(
+    manager: Arc<PaymentsManager>,
+    pending: Arc<PendingQueue>,
+    webhooks: Arc<Webhooks>,
+    retry: RetryPolicy,
+    scheduler: Arc<Scheduler>,
+    rx: Receiver,
+) -> Result<()> {
     loop {
         let req = rx.recv_async().await?;
 
-        let result = manager.send(req.clone()).await;
+        manager.wait_resumed().await;
 
-        if let Err(err) = result {
-            tracing::debug!(?err, "pp_client_err");
-            tx.send_async(req).await?;
-        }
+        let route = match req.processor {
+            Some(id) => manager.route_to(id).await,
+            None => manager.route().await,
+        };
+
+        let manager = manager.clone();
+        let pending = pending.clone();
+        let webhooks = webhooks.clone();
+        let scheduler = scheduler.clone();
+
+        tokio::spawn(async move {
+            let mut req = req;
+            let result = manager.send(&route, req.clone()).await;
+
+            // the route holds the processor permit until the retry is scheduled,
+            // so a draining worker never sees an empty queue with nothing in flight
+            match result {
+                Ok(()) => {
+                    let processor = manager.processor_name(&route).to_string();
+                    webhooks.notify(&req, Event::Processed { processor });
+                }
+                Err(err) => {
+                    tracing::debug!(?err, "pp_client_err");
+                    req.attempts += 1;
+
+                    let refused = pp_client::is_refused(&err);
+
+                    if pp_client::is_unresolved(&err) {
+                        req.processor = Some(route.processor());
+                    }
+
+                    let max_attempts = retry.max_attempts;
+
+                    if refused || (max_attempts > 0 && req.attempts >= max_attempts) {
+                        tracing::warn!(
+                            req.correlation_id,
+                            req.attempts,
+                            refused,
+                            "payment dead-lettered"
+                        );
+                        metrics::counter!("pp.dead_letter").increment(1);
+
+                        if let Err(err) = pending.complete(&req.correlation_id) {
+                            tracing::error!(?err, "pending_err");
+                        }
+
+                        webhooks.notify(&req, Event::Failed);
+                    } else {
+                        let delay = retry.delay(req.attempts).as_micros() as i64;
+                        req.scheduled_at = Some(chrono::Utc::now().timestamp_micros() + delay);
+
+                        scheduler.schedule(req);
+                    }
+                }
+            }
+
+            drop(route);
+        });
     }
 }
 
-async fn uds_listen(tx: Sender, store: db::Store) -> Result<()> {
-    let socket = get_worker_socket();
-    let listener = bind_unix_socket(&socket)?;
+/// State shared by every UDS connection.
+#[derive(Clone)]
+struct Context {
+    tx: Sender,
+    store: db::Store,
+    pending: Arc<PendingQueue>,
+    manager: Arc<PaymentsManager>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    scheduler: Arc<Scheduler>,
+}
 
-    tracing::info!("listening on {}", &socket);
+async fn uds_listen(socket: &str, ctx: Context, mut shutdown: Shutdown) -> Result<()> {
+    let listener = bind_unix_socket(socket)?;
+
+    tracing::info!("listening on {}", socket);
 
     loop {
-        let tx = tx.clone();
-        let store = store.clone();
+        let ctx = ctx.clone();
+        let shutdown_conn = shutdown.clone();
 
-        let (socket, _) = listener.accept().await?;
+        let (socket, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.wait() => return Ok(()),
+        };
         tracing::debug!("accepted unix socket connection");
 
         tokio::spawn(async {
-            if let Err(err) = handle_uds(tx, socket, store).await {
+            if let Err(err) = handle_uds(ctx, socket, shutdown_conn).await {
                 tracing::error!(err = ?err, "handle_uds");
             }
         });
     }
 }
 
-async fn handle_uds(tx: Sender, stream: UnixStream, store: db::Store) -> Result<()> {
+async fn handle_uds(ctx: Context, stream: UnixStream, mut shutdown: Shutdown) -> Result<()> {
     let mut stream = data::FramedStream::new(stream);
 
     loop {
-        let n = stream.read().await?;
+        let n = tokio::select! {
+            n = stream.read() => n?,
+            _ = shutdown.wait() => return Ok(()),
+        };
 
         if n == 0 {
             return Ok(());
@@ -111,32 +393,264 @@ async fn handle_uds(tx: Sender, stream: UnixStream, store: db::Store) -> Result<
             match req {
                 WorkerRequest::Summary(query) => {
                     let socket = stream.inner();
-                    summary::process(socket, &store, query).await?;
+                    summary::process(socket, &ctx.store, query).await?;
+                }
+                WorkerRequest::SummaryStream(scope) => {
+                    let interval =
+                        Duration::from_millis(env_or("SUMMARY_STREAM_INTERVAL_MS", 1_000));
+
+                    // the connection belongs to the stream from now on
+                    return summary::stream(
+                        &mut stream,
+                        &ctx.store,
+                        scope,
+                        interval,
+                        &mut shutdown,
+                    )
+                    .await;
+                }
+                WorkerRequest::Export(query) => {
+                    let chunk_size = env_or("EXPORT_CHUNK_SIZE", 1_000);
+                    export::export(stream.inner(), &ctx.store, query, chunk_size).await?
+                }
+                WorkerRequest::Import(records) => {
+                    import::import(stream.inner(), &ctx.store, records).await?
                 }
                 WorkerRequest::Payment(req) => {
                     tracing::trace!("sending to req_channel");
-                    tx.send_async(req).await?;
+                    accept(stream.inner(), &ctx, vec![req]).await?;
+                }
+                WorkerRequest::Batch(payments) => {
+                    tracing::trace!(payments = payments.len(), "sending batch to req_channel");
+                    accept(stream.inner(), &ctx, payments).await?;
                 }
-                WorkerRequest::PurgeDb => purge_db(&store).await?,
+                WorkerRequest::Status(req) => status::status(stream.inner(), &ctx, req).await?,
+                WorkerRequest::Cancel(req) => status::cancel(stream.inner(), &ctx, req).await?,
+                WorkerRequest::Refund(req) => refund::refund(stream.inner(), &ctx, req).await?,
+                WorkerRequest::Purge(req) => admin::purge(stream.inner(), &ctx, req).await?,
+                WorkerRequest::Stats => admin::stats(stream.inner(), &ctx).await?,
+                WorkerRequest::ForceRoute(processor) => {
+                    admin::force_route(stream.inner(), &ctx, processor).await?
+                }
+                WorkerRequest::RateLimit(key, wanted) => {
+                    let taken = match &ctx.rate_limiter {
+                        Some(limiter) => limiter.take(&key, wanted, Instant::now()),
+                        None => (wanted, None),
+                    };
+
+                    data::send_large(taken, stream.inner()).await?
+                }
+                WorkerRequest::Pause => ctx.manager.pause(),
+                WorkerRequest::Resume => ctx.manager.resume(),
             }
         }
     }
 }
 
-async fn purge_db(store: &db::Store) -> Result<()> {
-    store.purge().await;
-
-    tracing::info!("db purged");
-
-    Ok(())
-}
-
 #[derive(serde::Serialize, serde::Deserialize)]
 pub enum WorkerRequest {
-    Summary((i64, i64)),
+    Summary(api::summary::Query),
+    /// Turns the connection into a stream of `api::summary::Update`s.
+    SummaryStream(api::summary::Scope),
+    /// Answered with chunks of formatted records, then `None`.
+    Export(api::export::Export),
+    /// Bulk insert of already processed payments, answered with an
+    /// `import::ImportResult`.
+    Import(Vec<import::ImportRecord>),
+    /// Answered with `Result<(), String>` once journaled.
     Payment(api::payment::Request),
-    PurgeDb,
+    /// Answered like `Payment`, for all of them at once.
+    Batch(Vec<api::payment::Request>),
+    /// Takes up to that many tokens for a client key, answered with how many
+    /// it got and how long to wait when short.
+    RateLimit(String, u32),
+    Status(PaymentRef),
+    Cancel(PaymentRef),
+    Refund(PaymentRef),
+    Purge(admin::PurgeRequest),
+    Stats,
+    ForceRoute(Option<String>),
+    Pause,
+    Resume,
+}
+
+/// A single payment, as seen by a merchant or, on `Scope::All`, an admin.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PaymentRef {
+    pub correlation_id: String,
+    pub scope: api::summary::Scope,
 }
 
 type Sender = flume::Sender<api::payment::Request>;
 type Receiver = flume::Receiver<api::payment::Request>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockProcessor;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let mock = MockProcessor::new(0.05);
+        mock.set_delay(Duration::from_millis(200));
+
+        let url = mock.listen("127.0.0.1:0").await.expect("listen");
+        let pending = Arc::new(PendingQueue::disabled());
+
+        let manager = testing::manager(
+            &[testing::processor("default", url)],
+            db::Store::new(vec!["default".to_string()]),
+            pending.clone(),
+            &Client::new(),
+        );
+
+        let (tx, rx) = flume::unbounded();
+        tokio::spawn(dispatch(
+            manager.clone(),
+            pending,
+            Webhooks::disabled(),
+            RetryPolicy::from_env(),
+            Arc::new(Scheduler::new()),
+            rx,
+        ));
+
+        for i in 0..8 {
+            tx.send_async(testing::request(i)).await.expect("queue");
+        }
+
+        // the deadline passes with payments still queued and in flight
+        let left = drain(&tx, &manager, Duration::from_millis(50)).await;
+        assert!(left > 0);
+
+        assert_eq!(drain(&tx, &manager, Duration::from_secs(5)).await, 0);
+        assert_eq!(mock.len(), 8);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = RetryPolicy {
+            max_attempts: 0,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        };
+
+        assert_eq!(retry.delay(1), Duration::from_millis(50));
+        assert_eq!(retry.delay(3), Duration::from_millis(200));
+        assert_eq!(retry.delay(100), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_retry_backoff() {
+        let mock = MockProcessor::new(0.05);
+        mock.set_failing(true);
+
+        let url = mock.listen("127.0.0.1:0").await.expect("listen");
+        let pending = Arc::new(PendingQueue::disabled());
+
+        let manager = testing::manager(
+            &[testing::processor("default", url)],
+            db::Store::new(vec!["default".to_string()]),
+            pending.clone(),
+            &Client::new(),
+        );
+
+        let retry = RetryPolicy {
+            max_attempts: 0,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        };
+
+        let scheduler = Arc::new(Scheduler::new());
+        let (tx, rx) = flume::unbounded();
+        tokio::spawn({
+            let scheduler = scheduler.clone();
+            let tx = tx.clone();
+            async move { scheduler.run(tx).await }
+        });
+        tokio::spawn(dispatch(
+            manager.clone(),
+            pending,
+            Webhooks::disabled(),
+            retry,
+            scheduler.clone(),
+            rx,
+        ));
+
+        tx.send_async(testing::request(1)).await.expect("queue");
+
+        // 50 + 100 + 200 ms of backoff fit before the fourth attempt
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!((3..=5).contains(&mock.received()), "{}", mock.received());
+
+        // a retry waits in the scheduler, not in the queue
+        assert!(tx.is_empty());
+        assert_eq!(scheduler.len(), 1);
+
+        mock.set_failing(false);
+        assert_eq!(drain(&tx, &manager, Duration::from_secs(5)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_unresolved_payment_pinned() {
+        let a = MockProcessor::new(0.05);
+        let b = MockProcessor::new(0.05);
+        a.set_delay(Duration::from_millis(300));
+
+        let processors = [
+            testing::processor("a", a.listen("127.0.0.1:0").await.expect("listen")),
+            testing::processor("b", b.listen("127.0.0.1:0").await.expect("listen")),
+        ];
+        let store = db::Store::new(vec!["a".to_string(), "b".to_string()]);
+        let pending = Arc::new(PendingQueue::disabled());
+
+        let client = Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .expect("client");
+
+        let manager = testing::manager(&processors, store.clone(), pending.clone(), &client);
+
+        let scheduler = Arc::new(Scheduler::new());
+        let (tx, rx) = flume::unbounded();
+        tokio::spawn({
+            let scheduler = scheduler.clone();
+            let tx = tx.clone();
+            async move { scheduler.run(tx).await }
+        });
+        tokio::spawn(dispatch(
+            manager.clone(),
+            pending,
+            Webhooks::disabled(),
+            RetryPolicy::from_env(),
+            scheduler,
+            rx,
+        ));
+
+        manager.force(Some("a"));
+        tx.send_async(testing::request(1)).await.expect("queue");
+
+        // taken by `a`, whose answer times out and whose lookup then fails
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        a.set_failing(true);
+        manager.force(Some("b"));
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(b.received(), 0);
+
+        a.set_failing(false);
+        a.set_delay(Duration::ZERO);
+
+        let id = data::uuid_to_u128(&testing::request(1).correlation_id).expect("uuid");
+        let start = Instant::now();
+        while !store.contains(id) {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "payment not resolved"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(a.len(), 1);
+        assert_eq!(b.received(), 0);
+    }
+}
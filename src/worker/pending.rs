@@ -0,0 +1,460 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, mpsc},
+    thread::JoinHandle,
+};
+
+use anyhow::{Result, anyhow};
+use tokio::sync::oneshot;
+
+use crate::{
+    api::{payment, summary::Scope},
+    data,
+};
+
+/// Journal of the accepted payments not yet delivered to a processor,
+/// replayed and compacted on startup; in memory only without a path.
+pub struct PendingQueue {
+    writer: Option<Writer>,
+    /// Merchant of each pending payment, by correlation id.
+    pending: Mutex<HashMap<String, Option<String>>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+enum Entry {
+    Enqueued(payment::Request),
+    Done(String),
+}
+
+const SIZE: usize = std::mem::size_of::<u32>();
+
+impl PendingQueue {
+    pub fn disabled() -> Self {
+        Self {
+            writer: None,
+            pending: Mutex::default(),
+        }
+    }
+
+    /// Whether accepted payments survive a restart.
+    pub fn is_durable(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    /// Opens (or creates) the journal at `path`, returning the payments that
+    /// were still pending in the order they were accepted.
+    pub fn open(path: impl AsRef<Path>) -> Result<(Self, Vec<payment::Request>)> {
+        let path = path.as_ref();
+
+        let pending = match File::open(path) {
+            Ok(mut file) => {
+                let mut buf = Vec::new();
+                file.read_to_end(&mut buf)?;
+                replay(&buf)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        compact(path, &pending)?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+
+        let queue = Self {
+            writer: Some(Writer::start(file)?),
+            pending: Mutex::new(
+                pending
+                    .iter()
+                    .map(|req| (req.correlation_id.clone(), req.merchant.clone()))
+                    .collect(),
+            ),
+        };
+
+        Ok((queue, pending))
+    }
+
+    /// Records `reqs` as accepted, returning once they are on disk. On an
+    /// error they are tombstoned, as far as the journal still takes it.
+    pub async fn enqueue(&self, reqs: &[payment::Request]) -> Result<()> {
+        {
+            let mut pending = self.pending.lock().expect("pending lock");
+
+            for req in reqs {
+                pending.insert(req.correlation_id.clone(), req.merchant.clone());
+            }
+        }
+
+        let result = self.record(reqs).await;
+
+        if result.is_err() {
+            for req in reqs {
+                self.complete(&req.correlation_id).ok();
+            }
+        }
+
+        result
+    }
+
+    pub fn complete(&self, correlation_id: &str) -> Result<()> {
+        self.pending
+            .lock()
+            .expect("pending lock")
+            .remove(correlation_id);
+
+        self.append(&Entry::Done(correlation_id.to_string()))
+    }
+
+    /// Whether the payment was accepted within `scope` and not delivered yet.
+    pub fn contains(&self, correlation_id: &str, scope: &Scope) -> bool {
+        let pending = self.pending.lock().expect("pending lock");
+
+        pending
+            .get(correlation_id)
+            .is_some_and(|merchant| scope.allows(merchant.as_deref()))
+    }
+
+    async fn record(&self, reqs: &[payment::Request]) -> Result<()> {
+        for req in reqs {
+            self.append(&Entry::Enqueued(req.clone()))?;
+        }
+
+        self.flush().await
+    }
+
+    /// Waits until every record so far is on disk.
+    pub async fn flush(&self) -> Result<()> {
+        let Some(writer) = &self.writer else {
+            return Ok(());
+        };
+
+        let (tx, rx) = oneshot::channel();
+        writer.send(Command::Sync(tx))?;
+
+        rx.await?.map_err(|err| anyhow!(err))
+    }
+
+    fn append(&self, entry: &Entry) -> Result<()> {
+        let Some(writer) = &self.writer else {
+            return Ok(());
+        };
+
+        writer.send(Command::Append(record(entry)?))
+    }
+}
+
+enum Command {
+    Append(Vec<u8>),
+    Sync(oneshot::Sender<Result<(), String>>),
+}
+
+/// Writes the journal from its own thread, so the runtime never blocks on
+/// the disk, batching the records queued meanwhile.
+struct Writer {
+    tx: Option<mpsc::Sender<Command>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Writer {
+    fn start(mut file: File) -> Result<Self> {
+        let (tx, rx) = mpsc::channel::<Command>();
+
+        let thread = std::thread::Builder::new()
+            .name("pending-journal".to_string())
+            .spawn(move || {
+                let mut batch = Vec::new();
+
+                while let Ok(command) = rx.recv() {
+                    let mut syncs = Vec::new();
+
+                    for command in std::iter::once(command).chain(rx.try_iter()) {
+                        match command {
+                            Command::Append(record) => batch.extend_from_slice(&record),
+                            Command::Sync(reply) => syncs.push(reply),
+                        }
+                    }
+
+                    // a crash can only cut the last record short
+                    let mut result = file.write_all(&batch);
+                    batch.clear();
+
+                    if result.is_ok() && !syncs.is_empty() {
+                        result = file.sync_all();
+                    }
+
+                    if let Err(err) = &result {
+                        tracing::error!(?err, "pending_err");
+                    }
+
+                    for reply in syncs {
+                        reply
+                            .send(result.as_ref().map(|_| ()).map_err(|e| e.to_string()))
+                            .ok();
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            tx: Some(tx),
+            thread: Some(thread),
+        })
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        let tx = self.tx.as_ref().expect("writer running");
+
+        tx.send(command)
+            .map_err(|_| anyhow!("pending journal writer stopped"))
+    }
+}
+
+impl Drop for Writer {
+    /// Writes what is still queued before the journal can be reopened.
+    fn drop(&mut self) {
+        self.tx.take();
+
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn record(entry: &Entry) -> Result<Vec<u8>> {
+    let payload = data::encode_to_vec(entry)?;
+
+    let mut record = Vec::with_capacity(SIZE + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(&payload);
+
+    Ok(record)
+}
+
+fn replay(mut buf: &[u8]) -> Vec<payment::Request> {
+    let mut order = Vec::new();
+    let mut pending = HashMap::new();
+
+    while buf.len() >= SIZE {
+        let (len, rest) = buf.split_at(SIZE);
+        let len = u32::from_be_bytes(len.try_into().expect("u32 prefix")) as usize;
+
+        if rest.len() < len {
+            tracing::warn!("discarding truncated pending queue record");
+            break;
+        }
+
+        let (payload, rest) = rest.split_at(len);
+        buf = rest;
+
+        match data::decode(payload) {
+            Ok(Entry::Enqueued(req)) => {
+                order.push(req.correlation_id.clone());
+                pending.insert(req.correlation_id.clone(), req);
+            }
+            Ok(Entry::Done(id)) => {
+                pending.remove(&id);
+            }
+            Err(err) => {
+                tracing::warn!(?err, "discarding corrupted pending queue record");
+                break;
+            }
+        }
+    }
+
+    order.iter().filter_map(|id| pending.remove(id)).collect()
+}
+
+/// Rewrites the journal with only the pending payments, through a temporary
+/// file so a crash while compacting keeps the previous journal intact.
+fn compact(path: &Path, pending: &[payment::Request]) -> Result<()> {
+    let mut tmp = PathBuf::from(path);
+    tmp.set_extension("tmp");
+
+    {
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+
+        for req in pending {
+            writer.write_all(&record(&Entry::Enqueued(req.clone()))?)?;
+        }
+
+        writer.into_inner()?.sync_all()?;
+    }
+
+    std::fs::rename(tmp, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use reqwest::Client;
+    use tokio::runtime::Builder;
+
+    use super::*;
+    use crate::{
+        db,
+        mock::MockProcessor,
+        worker::{RetryPolicy, dispatch, scheduler::Scheduler, testing, webhook::Webhooks},
+    };
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rinha-{}-{name}", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let path = temp_path("replay");
+
+        let (queue, replayed) = PendingQueue::open(&path).expect("open");
+        assert!(replayed.is_empty());
+
+        let reqs: Vec<_> = (0..3).map(testing::request).collect();
+        queue.enqueue(&reqs).await.expect("enqueue");
+
+        // on disk once enqueued, before the writer stops
+        assert_eq!(replay(&std::fs::read(&path).expect("read")).len(), 3);
+        queue
+            .complete(&testing::request(1).correlation_id)
+            .expect("complete");
+        drop(queue);
+
+        // a record cut short by a crash is ignored
+        let mut file = OpenOptions::new().append(true).open(&path).expect("open");
+        file.write_all(&[0, 0, 0, 42, 1, 2]).expect("write");
+        drop(file);
+
+        let (_, replayed) = PendingQueue::open(&path).expect("reopen");
+        let ids: Vec<_> = replayed.iter().map(|r| r.correlation_id.clone()).collect();
+        assert_eq!(
+            ids,
+            [
+                testing::request(0).correlation_id,
+                testing::request(2).correlation_id
+            ]
+        );
+
+        let (_, replayed) = PendingQueue::open(&path).expect("reopen compacted");
+        assert_eq!(replayed.len(), 2);
+
+        std::fs::remove_file(path).ok();
+    }
+
+    /// Mock processor on its own thread, so it outlives the worker runtimes.
+    fn spawn_processor(delay: Duration) -> (String, Arc<MockProcessor>) {
+        let mock = MockProcessor::new(0.05);
+        mock.set_delay(delay);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let processor = mock.clone();
+        std::thread::spawn(move || {
+            let rt = Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("processor runtime");
+
+            rt.block_on(async move {
+                let url = processor.listen("127.0.0.1:0").await.expect("listen");
+                tx.send(url).expect("send url");
+
+                std::future::pending::<()>().await
+            });
+        });
+
+        (rx.recv().expect("processor url"), mock)
+    }
+
+    /// Replays the journal and drains it on a fresh runtime, dropped
+    /// mid-flight like a killed process once `processor` has `stop_after`.
+    fn run_worker(
+        path: &Path,
+        url: &str,
+        processor: &MockProcessor,
+        accept: &[payment::Request],
+        stop_after: usize,
+    ) -> usize {
+        let rt = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("worker runtime");
+
+        rt.block_on(async {
+            let (queue, replayed) = PendingQueue::open(path).expect("open");
+            let queue = Arc::new(queue);
+
+            let manager = testing::manager(
+                &[testing::processor("default", url.to_string())],
+                db::Store::new(vec!["default".to_string()]),
+                queue.clone(),
+                &Client::new(),
+            );
+
+            let (tx, rx) = flume::unbounded();
+
+            let replayed_count = replayed.len();
+            for req in replayed {
+                tx.send(req).expect("replay");
+            }
+
+            for req in accept {
+                queue
+                    .enqueue(std::slice::from_ref(req))
+                    .await
+                    .expect("enqueue");
+                tx.send(req.clone()).expect("accept");
+            }
+
+            tokio::spawn(dispatch(
+                manager.clone(),
+                queue.clone(),
+                Webhooks::disabled(),
+                RetryPolicy::from_env(),
+                Arc::new(Scheduler::new()),
+                rx,
+            ));
+
+            let start = std::time::Instant::now();
+            while (!tx.is_empty() || manager.inflight() > 0) && processor.len() < stop_after {
+                assert!(start.elapsed() < Duration::from_secs(10), "worker stuck");
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+
+            replayed_count
+        })
+    }
+
+    #[test]
+    fn test_kill_mid_drain() {
+        let path = temp_path("kill");
+        let (url, processor) = spawn_processor(Duration::from_millis(10));
+
+        let accepted: Vec<_> = (0..100).map(testing::request).collect();
+
+        run_worker(&path, &url, &processor, &accepted, 30);
+
+        let delivered = processor.len();
+        assert!(delivered >= 30 && delivered < accepted.len());
+
+        let replayed = run_worker(&path, &url, &processor, &[], usize::MAX);
+        assert!(replayed >= accepted.len() - delivered);
+
+        for req in &accepted {
+            assert!(processor.get(&req.correlation_id).is_some());
+        }
+
+        // only the payments in flight when the worker died (at most the
+        // concurrency limit) may be sent again, and the processor refuses those
+        assert_eq!(processor.len(), accepted.len());
+        assert!(processor.received() as usize - accepted.len() <= 4);
+
+        let (_, pending) = PendingQueue::open(&path).expect("reopen");
+        assert!(pending.is_empty());
+
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -1,163 +1,605 @@
 use std::{
     sync::{
         Arc,
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
     },
     time::{Duration, Instant},
 };
 
 use anyhow::{Result, anyhow};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use metrics::Unit;
 use reqwest::{Client, StatusCode};
+use tokio::sync::watch;
 
 use crate::{
     api::payment,
-    data::{Payment, ProcessorPaymentRequest},
+    data::{self, Payment, ProcessorPaymentRequest, ProcessorRefundRequest},
     db,
+    worker::{
+        admin::ProcessorStats,
+        estimator::Estimator,
+        limiter::{Limiter, LimiterConfig, Permit},
+        pending::PendingQueue,
+        routing::{Candidate, RoutingStrategy},
+    },
 };
 
 pub struct PaymentsManager {
-    default: PaymentProcesorClient,
-    fallback: PaymentProcesorClient,
+    processors: Vec<PaymentProcesorClient>,
     store: db::Store,
-    micros_cutout: u64,
+    pending: Arc<PendingQueue>,
+    strategy: Box<dyn RoutingStrategy>,
+    forced: AtomicUsize,
+    paused: watch::Sender<bool>,
 }
 
+/// `PaymentsManager::forced` value when the strategy is in charge.
+const NOT_FORCED: usize = usize::MAX;
+
 impl PaymentsManager {
     pub fn new(
-        default: &str,
-        fallback: &str,
-        micros_cutout: u64,
+        processors: &[ProcessorConfig],
+        strategy: Box<dyn RoutingStrategy>,
+        limits: LimiterConfig,
+        alpha: f64,
         store: db::Store,
+        pending: Arc<PendingQueue>,
         client: &Client,
     ) -> Arc<Self> {
+        let processors = processors
+            .iter()
+            .enumerate()
+            .map(|(id, config)| {
+                PaymentProcesorClient::new(
+                    id as u8,
+                    config,
+                    Estimator::new(alpha),
+                    Limiter::new(limits),
+                    client.clone(),
+                )
+            })
+            .collect();
+
         Arc::new(Self {
-            default: PaymentProcesorClient::new(0, default, client.clone()),
-            fallback: PaymentProcesorClient::new(1, fallback, client.clone()),
+            processors,
             store,
-            micros_cutout,
+            pending,
+            strategy,
+            forced: AtomicUsize::new(NOT_FORCED),
+            paused: watch::Sender::new(false),
         })
     }
 
-    pub async fn send(&self, req: payment::Request) -> Result<()> {
-        let client = self.get_client();
+    /// Picks a processor and waits until its concurrency limit has room
+    /// for one more call.
+    pub async fn route(&self) -> Route {
+        self.route_to(self.get_client() as u8).await
+    }
+
+    /// Like `route`, but always to processor `id`.
+    pub async fn route_to(&self, id: u8) -> Route {
+        let id = id as usize;
+
+        let permit = self.processors[id].limiter.acquire().await;
+
+        Route {
+            id,
+            _permit: permit,
+        }
+    }
+
+    pub async fn send(&self, route: &Route, req: payment::Request) -> Result<()> {
+        let client = &self.processors[route.id];
+        let correlation_id = req.correlation_id.clone();
+
+        let merchant_id = self.store.merchant_id(req.merchant.as_deref());
+
+        let payment = match client.send(req, merchant_id).await {
+            Ok(payment) => payment,
+            Err(err) => match err.downcast::<AlreadyProcessed>() {
+                Ok(AlreadyProcessed(payment)) => {
+                    tracing::warn!(
+                        correlation_id,
+                        pp = client.name,
+                        "payment already processed"
+                    );
+                    metrics::counter!("pp.duplicate", "processor" => client.name.clone())
+                        .increment(1);
+
+                    self.complete(&correlation_id);
+
+                    // an earlier attempt went through, e.g. before a crash
+                    if !self.store.contains(payment.correlation_id) {
+                        self.store.insert(payment).await;
+                    }
+
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            },
+        };
 
-        let payment = client.send(req).await?;
+        // tombstoned before any other await, so a worker killed between the
+        // processor's answer and this line can not happen inside the runtime
+        self.complete(&correlation_id);
 
         self.store.insert(payment).await;
 
         Ok(())
     }
 
+    /// The processor has the payment, so a failed journal write only means
+    /// it may be resent (and refused as a duplicate) after a restart.
+    fn complete(&self, correlation_id: &str) {
+        if let Err(err) = self.pending.complete(correlation_id) {
+            tracing::error!(?err, correlation_id, "pending_err");
+        }
+    }
+
+    /// Forwards the refund of `payment` to the processor it went through and
+    /// records it in the ledger.
+    pub async fn refund(&self, payment: Payment) -> Result<()> {
+        let client = &self.processors[payment.processor_id as usize];
+
+        let requested_at = client.refund(&payment).await?;
+
+        let refund = Payment {
+            requested_at,
+            refund: true,
+            ..payment
+        };
+
+        self.store.insert(refund).await;
+
+        Ok(())
+    }
+
     pub fn start(self: &Arc<Self>, secs: u64) {
         let m = self.clone();
 
         tokio::spawn(async move {
+            m.fetch_fees().await;
+
             loop {
                 tokio::time::sleep(Duration::from_secs(secs)).await;
-                m.reset();
+                m.probe().await;
             }
         });
     }
 
-    fn reset(&self) {
-        let default = self.default.latency.swap(0, Ordering::Relaxed);
-        let fallback = self.fallback.latency.swap(0, Ordering::Relaxed);
+    async fn fetch_fees(&self) {
+        for p in self.processors.iter().filter(|p| p.fetch_fee) {
+            match p.fetch_fee().await {
+                Ok(fee) => tracing::info!("{}: fetched fee {fee}", p.name),
+                Err(err) => tracing::warn!(?err, "{}: could not fetch fee", p.name),
+            }
+        }
+    }
+
+    /// Feeds each processor's health check into its estimator, so one that
+    /// stopped receiving traffic after failing can still be seen recovering.
+    async fn probe(&self) {
+        for p in &self.processors {
+            if let Err(err) = p.probe().await {
+                tracing::debug!(?err, "{}: health check failed", p.name);
+            }
+
+            let estimate = p.estimator.estimate();
+            let limit = p.limiter.limit();
+            let inflight = p.limiter.inflight();
+
+            tracing::info!(
+                "{}: p50 {} | p99 {} | ewma {} | failure rate {:.3} | limit {inflight}/{limit}",
+                p.name,
+                estimate.p50,
+                estimate.p99,
+                estimate.ewma,
+                estimate.failure_rate,
+            );
+        }
+    }
+
+    pub fn processor_name(&self, route: &Route) -> &str {
+        &self.processors[route.id].name
+    }
+
+    pub fn inflight(&self) -> usize {
+        self.processors.iter().map(|p| p.limiter.inflight()).sum()
+    }
+
+    /// Sends every payment to `processor`, or back to the strategy on `None`.
+    /// Returns false when there is no processor with that name.
+    pub fn force(&self, processor: Option<&str>) -> bool {
+        let id = match processor {
+            Some(name) => match self.processors.iter().position(|p| p.name == name) {
+                Some(id) => id,
+                None => return false,
+            },
+            None => NOT_FORCED,
+        };
+
+        self.forced.store(id, Ordering::Relaxed);
+
+        true
+    }
+
+    pub fn forced(&self) -> Option<String> {
+        let id = self.forced.load(Ordering::Relaxed);
+
+        self.processors.get(id).map(|p| p.name.clone())
+    }
+
+    pub fn pause(&self) {
+        tracing::info!("pausing dispatch");
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        tracing::info!("resuming dispatch");
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub async fn wait_resumed(&self) {
+        // the sender lives in `self`, so the channel can not be closed here
+        _ = self.paused.subscribe().wait_for(|paused| !paused).await;
+    }
+
+    pub fn stats(&self) -> Vec<ProcessorStats> {
+        self.processors
+            .iter()
+            .map(|p| {
+                let estimate = p.estimator.estimate();
+
+                ProcessorStats {
+                    name: p.name.clone(),
+                    fee: f64::from_bits(p.fee.load(Ordering::Relaxed)),
+                    weight: p.weight,
+                    limit: p.limiter.limit(),
+                    inflight: p.limiter.inflight(),
+                    p50: estimate.p50,
+                    p99: estimate.p99,
+                    ewma: estimate.ewma,
+                    failure_rate: estimate.failure_rate,
+                }
+            })
+            .collect()
+    }
+
+    fn get_client(&self) -> usize {
+        let forced = self.forced.load(Ordering::Relaxed);
+
+        if forced != NOT_FORCED {
+            return forced;
+        }
+
+        let candidates: Vec<_> = self.processors.iter().map(|p| p.candidate()).collect();
+
+        self.strategy.choose(&candidates)
+    }
+}
+
+pub struct Route {
+    id: usize,
+    _permit: Permit,
+}
+
+impl Route {
+    pub fn processor(&self) -> u8 {
+        self.id as u8
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessorConfig {
+    pub name: String,
+    pub url: String,
+    /// Fee rate charged per payment, fetched from the processor when `None`.
+    pub fee: Option<f64>,
+    pub weight: u32,
+}
+
+impl ProcessorConfig {
+    /// Reads the processors from `PROCESSORS`, formatted as
+    /// `name=url[;fee=0.05][;weight=1],...`, falling back to the
+    /// `PROCESSOR_DEFAULT` / `PROCESSOR_FALLBACK` pair.
+    pub fn from_env() -> Result<Vec<Self>> {
+        if let Ok(processors) = std::env::var("PROCESSORS") {
+            return Self::parse_list(&processors);
+        }
+
+        let default = std::env::var("PROCESSOR_DEFAULT")
+            .unwrap_or("http://payment-processor-default:8080".to_string());
+
+        let fallback = std::env::var("PROCESSOR_FALLBACK")
+            .unwrap_or("http://payment-processor-fallback:8080".to_string());
+
+        Ok(vec![
+            Self {
+                name: "default".to_string(),
+                url: default,
+                fee: Some(0.05),
+                weight: 1,
+            },
+            Self {
+                name: "fallback".to_string(),
+                url: fallback,
+                fee: Some(0.15),
+                weight: 1,
+            },
+        ])
+    }
+
+    fn parse_list(input: &str) -> Result<Vec<Self>> {
+        let processors = input
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(Self::parse)
+            .collect::<Result<Vec<_>>>()?;
+
+        if processors.is_empty() || processors.len() > u8::MAX as usize {
+            return Err(anyhow!("Invalid processor count {}", processors.len()));
+        }
+
+        for (i, p) in processors.iter().enumerate() {
+            if processors[..i].iter().any(|other| other.name == p.name) {
+                return Err(anyhow!("Duplicate processor {:?}", p.name));
+            }
+        }
 
-        tracing::info!("default: {default} | fallback: {fallback}");
+        Ok(processors)
     }
 
-    fn get_client(&self) -> &PaymentProcesorClient {
-        let default = self.default.latency.load(Ordering::Relaxed);
-        let fallback = self.fallback.latency.load(Ordering::Relaxed);
+    fn parse(input: &str) -> Result<Self> {
+        let mut fields = input.split(';');
 
-        if default <= fallback + self.micros_cutout {
-            &self.default
-        } else {
-            &self.fallback
+        let (name, url) = fields
+            .next()
+            .and_then(|f| f.split_once('='))
+            .ok_or_else(|| anyhow!("Invalid processor {input:?}"))?;
+
+        let mut config = Self {
+            name: name.trim().to_string(),
+            url: url.trim().to_string(),
+            fee: None,
+            weight: 1,
+        };
+
+        for field in fields {
+            match field.trim().split_once('=') {
+                Some(("fee", fee)) => config.fee = Some(fee.parse()?),
+                Some(("weight", weight)) => config.weight = weight.parse()?,
+                _ => return Err(anyhow!("Invalid processor field {field:?}")),
+            }
         }
+
+        Ok(config)
     }
 }
 
 struct PaymentProcesorClient {
     id: u8,
+    name: String,
+    fee: AtomicU64,
+    fetch_fee: bool,
+    weight: u32,
     client: Client,
     payments_url: String,
-    latency: AtomicU64,
-    start: Instant,
+    admin_summary_url: String,
+    health_url: String,
+    estimator: Estimator,
+    limiter: Arc<Limiter>,
 }
 
 impl PaymentProcesorClient {
-    fn new(id: u8, host: &str, client: Client) -> Self {
+    fn new(
+        id: u8,
+        config: &ProcessorConfig,
+        estimator: Estimator,
+        limiter: Arc<Limiter>,
+        client: Client,
+    ) -> Self {
         Self {
-            payments_url: format!("{host}/payments"),
-            latency: AtomicU64::new(0),
+            payments_url: format!("{}/payments", config.url),
+            admin_summary_url: format!("{}/admin/payments-summary", config.url),
+            health_url: format!("{}/payments/service-health", config.url),
+            name: config.name.clone(),
+            fee: AtomicU64::new(config.fee.unwrap_or_default().to_bits()),
+            fetch_fee: config.fee.is_none(),
+            weight: config.weight,
+            estimator,
+            limiter,
             client,
             id,
-            start: Instant::now(),
         }
     }
 
-    fn store_metrics(&self, latency: u64) {
-        const ORD: Ordering = Ordering::Relaxed;
+    fn candidate(&self) -> Candidate {
+        Candidate {
+            fee: f64::from_bits(self.fee.load(Ordering::Relaxed)),
+            weight: self.weight,
+            estimate: self.estimator.estimate(),
+        }
+    }
 
-        let new_age = self.start.elapsed().as_millis() as u64;
+    async fn probe(&self) -> Result<()> {
+        let health: ServiceHealth = self
+            .client
+            .get(&self.health_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
 
-        loop {
-            let current = self.latency.load(ORD);
-            let age = current >> 32;
+        match health.failing {
+            true => self.estimator.record_outcome(false),
+            false => self
+                .estimator
+                .record(health.min_response_time * 1_000, true),
+        }
 
-            if new_age <= age {
-                return;
-            }
+        Ok(())
+    }
 
-            let new = (new_age << 32) | latency;
+    async fn fetch_fee(&self) -> Result<f64> {
+        let token = std::env::var("PROCESSOR_TOKEN").unwrap_or("123".to_string());
 
-            let result = self.latency.compare_exchange(current, new, ORD, ORD);
+        let summary: ProcessorAdminSummary = self
+            .client
+            .get(&self.admin_summary_url)
+            .header("X-Rinha-Token", token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
 
-            if result.is_ok() {
-                return;
-            }
-        }
+        self.fee
+            .store(summary.fee_per_transaction.to_bits(), Ordering::Relaxed);
+
+        Ok(summary.fee_per_transaction)
     }
 
-    async fn send(&self, payment: payment::Request) -> Result<Payment> {
+    async fn send(&self, payment: payment::Request, merchant_id: u32) -> Result<Payment> {
+        let stamp = payment
+            .requested_at
+            .and_then(DateTime::from_timestamp_micros);
+
         let payment = ProcessorPaymentRequest {
-            requested_at: Utc::now(),
+            requested_at: stamp.unwrap_or_else(Utc::now),
             amount: payment.amount,
             correlation_id: payment.correlation_id,
+            currency: payment.currency,
         };
 
-        tracing::trace!(pp_id = self.id, "sending to payment-processor");
+        tracing::trace!(pp = self.name, "sending to payment-processor");
 
         let now = Instant::now();
 
         let result = self.http_send(&payment).await;
 
-        let elapsed = now.elapsed().as_micros();
+        let elapsed = now.elapsed();
+        let outcome = Outcome::of(&result);
 
-        let latency = match result {
-            Ok(_) => elapsed,
-            Err(_) => u128::MAX,
-        };
+        self.limiter.record(elapsed, matches!(outcome, Outcome::Ok));
 
-        self.store_metrics(latency as u64);
+        metrics::describe_gauge!("pp.limit", "payment processor concurrency limit");
+        metrics::gauge!("pp.limit", "processor" => self.name.clone())
+            .set(self.limiter.limit() as f64);
 
-        metrics::describe_histogram!("pp_http", Unit::Microseconds, "payment processor http time");
-        metrics::histogram!("pp_http").record(elapsed as f64);
+        let elapsed = elapsed.as_micros() as u64;
 
-        result?;
+        match outcome {
+            Outcome::Ok => self.estimator.record(elapsed, true),
+            // a timeout is kept as a (slow) latency sample so routing moves
+            // away from a hanging processor and not only from a failing one
+            Outcome::Timeout => {
+                self.estimator.record(elapsed, false);
+                metrics::counter!("pp_http.timeout", "processor" => self.name.clone()).increment(1);
+            }
+            Outcome::Failed => {
+                self.estimator.record_outcome(false);
+                metrics::counter!("pp_http.error", "processor" => self.name.clone()).increment(1);
+            }
+        }
 
-        let amount = payment.amount * 100.0;
+        metrics::describe_histogram!("pp_http", Unit::Microseconds, "payment processor http time");
+        metrics::histogram!("pp_http", "processor" => self.name.clone()).record(elapsed as f64);
 
-        let payment = Payment {
-            amount: amount as u64,
+        let mut processed = Payment {
+            correlation_id: data::uuid_to_u128(&payment.correlation_id).unwrap_or_default(),
+            amount: payment.amount,
             requested_at: payment.requested_at.timestamp_micros(),
             processor_id: self.id,
+            merchant_id,
+            currency: payment.currency,
+            refund: false,
+        };
+
+        let unprocessable = match &result {
+            Ok(()) => return Ok(processed),
+            Err(err) if err.is::<Unprocessable>() => true,
+            Err(err) if is_ambiguous(err) => false,
+            Err(_) => return result.map(|()| processed),
+        };
+
+        // a 422 is a duplicate or a refusal, and a timed out call may have
+        // gone through, so the processor is asked
+        let lookup = self.lookup(&payment.correlation_id).await;
+        let resolution = lookup.label();
+
+        tracing::info!(
+            correlation_id = payment.correlation_id,
+            pp = self.name,
+            unprocessable,
+            resolution,
+            "payment looked up"
+        );
+
+        let name = self.name.clone();
+        match unprocessable {
+            true => metrics::counter!("pp.unprocessable", "processor" => name, "resolution" => resolution)
+                .increment(1),
+            false => metrics::counter!("pp.ambiguous", "processor" => name, "resolution" => resolution)
+                .increment(1),
+        }
+
+        match lookup {
+            Lookup::Processed(requested_at) => {
+                processed.requested_at = requested_at;
+
+                match unprocessable {
+                    true => Err(AlreadyProcessed(processed).into()),
+                    false => Ok(processed),
+                }
+            }
+            // only resent when the processor has no record of it
+            Lookup::NotFound => result.map(|()| processed),
+            Lookup::Unknown => Err(Unresolved.into()),
+        }
+    }
+
+    /// Whether the processor has the payment, and its `requestedAt` there.
+    async fn lookup(&self, correlation_id: &str) -> Lookup {
+        let url = format!("{}/{correlation_id}", self.payments_url);
+
+        match self.client.get(url).send().await {
+            Ok(res) if res.status() == StatusCode::OK => match res.json::<ProcessorPayment>().await
+            {
+                Ok(payment) => Lookup::Processed(payment.requested_at.timestamp_micros()),
+                Err(_) => Lookup::Unknown,
+            },
+            Ok(res) if res.status() == StatusCode::NOT_FOUND => Lookup::NotFound,
+            _ => Lookup::Unknown,
+        }
+    }
+
+    /// Returns when the refund was requested, in micros.
+    async fn refund(&self, payment: &Payment) -> Result<i64> {
+        let refund = ProcessorRefundRequest {
+            requested_at: Utc::now(),
+            amount: payment.amount,
+            correlation_id: data::u128_to_uuid(payment.correlation_id),
+            currency: payment.currency,
         };
 
-        Ok(payment)
+        let url = format!("{}/{}/refund", self.payments_url, refund.correlation_id);
+
+        let status = self.client.post(url).json(&refund).send().await?.status();
+
+        metrics::counter!("pp.refund", "processor" => self.name.clone(), "status" => status.as_str().to_string())
+            .increment(1);
+
+        match status {
+            StatusCode::OK => Ok(refund.requested_at.timestamp_micros()),
+            _ => Err(anyhow!("{status}")),
+        }
     }
 
     async fn http_send(&self, payment: &ProcessorPaymentRequest) -> Result<()> {
@@ -174,7 +616,309 @@ impl PaymentProcesorClient {
 
         match status {
             StatusCode::OK => Ok(()),
+            StatusCode::UNPROCESSABLE_ENTITY => Err(Unprocessable.into()),
             _ => Err(anyhow!("{status}")),
         }
     }
 }
+
+/// A `422` from the processor, a duplicate or a refused payment.
+#[derive(Debug)]
+struct Unprocessable;
+
+impl std::fmt::Display for Unprocessable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "payment refused by the processor")
+    }
+}
+
+impl std::error::Error for Unprocessable {}
+
+/// Whether the processor refused the payment outright, which no retry will
+/// change.
+pub fn is_refused(err: &anyhow::Error) -> bool {
+    err.is::<Unprocessable>()
+}
+
+/// The processor may have taken the payment but could not be asked, so it is
+/// only retried there, where a second attempt is refused as a duplicate.
+#[derive(Debug)]
+struct Unresolved;
+
+impl std::fmt::Display for Unresolved {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "payment outcome unknown")
+    }
+}
+
+impl std::error::Error for Unresolved {}
+
+pub fn is_unresolved(err: &anyhow::Error) -> bool {
+    err.is::<Unresolved>()
+}
+
+/// The processor already has the payment, e.g. when replaying one sent right
+/// before a crash, as it recorded it.
+#[derive(Debug)]
+struct AlreadyProcessed(Payment);
+
+impl std::fmt::Display for AlreadyProcessed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "payment already processed")
+    }
+}
+
+impl std::error::Error for AlreadyProcessed {}
+
+/// A timeout or a connection dropped after the request went out, so the
+/// processor may have taken the payment without us seeing its answer.
+fn is_ambiguous(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .is_some_and(|err| !err.is_connect())
+}
+
+enum Lookup {
+    Processed(i64),
+    NotFound,
+    Unknown,
+}
+
+impl Lookup {
+    fn label(&self) -> &'static str {
+        match self {
+            Lookup::Processed(_) => "processed",
+            Lookup::NotFound => "not_found",
+            Lookup::Unknown => "unknown",
+        }
+    }
+}
+
+enum Outcome {
+    Ok,
+    Timeout,
+    Failed,
+}
+
+impl Outcome {
+    fn of<T>(result: &Result<T>) -> Self {
+        match result {
+            Ok(_) => Outcome::Ok,
+            Err(err) if err.is::<Unprocessable>() => Outcome::Ok,
+            Err(err) => match err.downcast_ref::<reqwest::Error>() {
+                Some(err) if err.is_timeout() => Outcome::Timeout,
+                _ => Outcome::Failed,
+            },
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ServiceHealth {
+    failing: bool,
+    min_response_time: u64,
+}
+
+/// `GET /payments/{correlationId}` on a processor.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProcessorPayment {
+    requested_at: DateTime<Utc>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProcessorAdminSummary {
+    fee_per_transaction: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::summary::Scope, mock::MockProcessor, worker::testing};
+
+    #[test]
+    fn test_parse_processors() {
+        let input = "default=http://pp-default:8080;fee=0.05;weight=3, fallback=http://pp-fallback:8080;fee=0.15,backup=http://pp-backup:8080";
+
+        let result = ProcessorConfig::parse_list(input).expect("parse processors");
+
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0].name, "default");
+        assert_eq!(result[0].url, "http://pp-default:8080");
+        assert_eq!(result[0].fee, Some(0.05));
+        assert_eq!(result[0].weight, 3);
+        assert_eq!(result[1].weight, 1);
+        assert_eq!(result[2].fee, None);
+
+        assert!(ProcessorConfig::parse_list("default").is_err());
+        assert!(ProcessorConfig::parse_list("default=http://pp;cost=1").is_err());
+        assert!(ProcessorConfig::parse_list("default=http://pp-a,default=http://pp-b").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refund_same_processor() {
+        let default = MockProcessor::new(0.05);
+        let fallback = MockProcessor::new(0.05);
+        let default_url = default.listen("127.0.0.1:0").await.expect("listen");
+        let fallback_url = fallback.listen("127.0.0.1:0").await.expect("listen");
+
+        let processors = [
+            testing::processor("default", default_url),
+            testing::processor("fallback", fallback_url),
+        ];
+
+        let store = db::Store::new(vec!["default".to_string(), "fallback".to_string()]);
+
+        let manager = testing::manager(
+            &processors,
+            store.clone(),
+            Arc::new(PendingQueue::disabled()),
+            &Client::new(),
+        );
+
+        let req = testing::request(1);
+        let id = req.correlation_id.clone();
+
+        manager.force(Some("fallback"));
+        let route = manager.route().await;
+        manager.send(&route, req).await.expect("send");
+        drop(route);
+        manager.force(None);
+
+        let scope = crate::api::summary::Scope::All;
+        let original = store
+            .start_refund(data::uuid_to_u128(&id).expect("uuid"), &scope)
+            .expect("refundable");
+        manager.refund(original).await.expect("refund");
+
+        assert_eq!(default.len(), 0);
+        assert!(fallback.get(&id).expect("in the fallback ledger").refunded);
+
+        let summary = store.get((0, i64::MAX), &scope).await;
+        let fallback = summary[0].get("fallback").expect("fallback");
+        assert_eq!(
+            (fallback.count, fallback.refunds, fallback.net),
+            (1, 1, 0.0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stamp_kept_across_attempts() {
+        let mock = MockProcessor::new(0.05);
+        let url = mock.listen("127.0.0.1:0").await.expect("listen");
+        let processors = [testing::processor("default", url)];
+
+        let accepted_at = Utc::now().timestamp_micros() - 1_000_000;
+
+        // stamped at acceptance, and on each attempt
+        for (i, requested_at) in [(1, Some(accepted_at)), (2, None)] {
+            let store = db::Store::new(vec!["default".to_string()]);
+
+            let manager = testing::manager(
+                &processors,
+                store.clone(),
+                Arc::new(PendingQueue::disabled()),
+                &Client::new(),
+            );
+
+            let req = payment::Request {
+                attempts: 1,
+                requested_at,
+                ..testing::request(i)
+            };
+            let id = data::uuid_to_u128(&req.correlation_id).expect("uuid");
+
+            // an earlier attempt got through, but its answer was lost
+            let route = manager.route().await;
+            manager
+                .send(&route, req.clone())
+                .await
+                .expect("first attempt");
+            store.purge(None).await;
+            manager.send(&route, req.clone()).await.expect("retry");
+
+            let processed = mock.get(&req.correlation_id).expect("processed");
+            if let Some(requested_at) = requested_at {
+                assert_eq!(processed.requested_at, requested_at);
+            }
+
+            // stored as the processor holds it
+            let (stored, _) = store.find(id, &Scope::All).expect("stored");
+            assert_eq!(stored.requested_at, processed.requested_at);
+            assert_eq!(store.count(None).await, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refused_payment() {
+        let mock = MockProcessor::new(0.05);
+        let url = mock.listen("127.0.0.1:0").await.expect("listen");
+        let processors = [testing::processor("default", url)];
+
+        let store = db::Store::new(vec!["default".to_string()]);
+
+        let manager = testing::manager(
+            &processors,
+            store.clone(),
+            Arc::new(PendingQueue::disabled()),
+            &Client::new(),
+        );
+
+        let req = payment::Request {
+            amount: 0,
+            ..testing::request(1)
+        };
+
+        // a 422 the processor has no record for is not a duplicate
+        let route = manager.route().await;
+        let err = manager.send(&route, req).await.expect_err("refused");
+        assert!(err.is::<Unprocessable>());
+        assert_eq!(store.count(None).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_ambiguous_lookup() {
+        let mock = MockProcessor::new(0.05);
+        mock.set_delay(Duration::from_millis(300));
+
+        let url = mock.listen("127.0.0.1:0").await.expect("listen");
+        let processors = [testing::processor("default", url)];
+
+        let store = db::Store::new(vec!["default".to_string()]);
+
+        let client = Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .expect("client");
+
+        let manager = testing::manager(
+            &processors,
+            store.clone(),
+            Arc::new(PendingQueue::disabled()),
+            &client,
+        );
+
+        let req = testing::request;
+
+        // taken by the processor, the answer just came too late
+        let route = manager.route().await;
+        manager.send(&route, req(1)).await.expect("resolved");
+
+        let processed = mock.get(&req(1).correlation_id).expect("processed");
+        let (stored, _) = store
+            .find(
+                data::uuid_to_u128(&req(1).correlation_id).expect("uuid"),
+                &Scope::All,
+            )
+            .expect("stored");
+        assert_eq!(stored.requested_at, processed.requested_at);
+
+        // refused after the timeout, so it is left for a retry
+        mock.set_failing(true);
+        assert!(manager.send(&route, req(2)).await.is_err());
+        let id = data::uuid_to_u128(&req(2).correlation_id).expect("uuid");
+        assert!(store.find(id, &Scope::All).is_none());
+        assert_eq!(mock.received(), 2);
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::{api::merchant, env_or};
+
+/// Buckets kept before idle ones are dropped.
+const MAX_BUCKETS: usize = 100_000;
+
+/// Bucket of every client without a known key.
+const SHARED: &str = "";
+
+/// Refills `rate` tokens per second, holding at most `burst`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl Quota {
+    fn new(rate: f64, burst: f64) -> Result<Self> {
+        if !(rate > 0.0 && burst >= 1.0) {
+            return Err(anyhow!("Invalid quota rate={rate} burst={burst}"));
+        }
+
+        Ok(Self { rate, burst })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, quota: &Quota, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * quota.rate).min(quota.burst);
+        self.updated = now;
+    }
+
+    /// Whether it would be full by `now`, which is the same as missing.
+    fn is_full(&self, quota: &Quota, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        self.tokens + elapsed * quota.rate >= quota.burst
+    }
+}
+
+/// Token buckets per client key, shared by every API instance through the
+/// worker so the limit is global.
+pub struct RateLimiter {
+    default: Option<Quota>,
+    quotas: HashMap<String, Quota>,
+    /// `MERCHANT_API_KEYS`, the other keys with a bucket of their own.
+    known: HashSet<String>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    max_buckets: usize,
+}
+
+impl RateLimiter {
+    pub fn new(
+        default: Option<Quota>,
+        quotas: HashMap<String, Quota>,
+        known: HashSet<String>,
+    ) -> Self {
+        Self {
+            default,
+            quotas,
+            known,
+            buckets: Mutex::new(HashMap::new()),
+            max_buckets: MAX_BUCKETS,
+        }
+    }
+
+    /// `RATE_LIMIT_RPS`/`RATE_LIMIT_BURST` apply to every key and
+    /// `RATE_LIMIT_QUOTAS` (`key=rps;burst=..,...`) overrides single keys;
+    /// `None` when neither is set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let default = match std::env::var("RATE_LIMIT_RPS") {
+            Ok(rate) => {
+                let rate = rate.parse()?;
+                Some(Quota::new(rate, env_or("RATE_LIMIT_BURST", rate))?)
+            }
+            Err(_) => None,
+        };
+
+        let quotas = match std::env::var("RATE_LIMIT_QUOTAS") {
+            Ok(quotas) => parse_quotas(&quotas)?,
+            Err(_) => HashMap::new(),
+        };
+
+        if default.is_none() && quotas.is_empty() {
+            return Ok(None);
+        }
+
+        let known = merchant::api_keys().keys().cloned().collect();
+
+        Ok(Some(Self::new(default, quotas, known)))
+    }
+
+    fn quota(&self, key: &str) -> Option<&Quota> {
+        self.quotas.get(key).or(self.default.as_ref())
+    }
+
+    /// Takes up to `wanted` tokens from `key`'s bucket, shared by all
+    /// unknown keys, with the wait for the next one when short.
+    pub fn take(&self, key: &str, wanted: u32, now: Instant) -> (u32, Option<Duration>) {
+        let key = match self.quotas.contains_key(key) || self.known.contains(key) {
+            true => key,
+            false => SHARED,
+        };
+
+        let Some(quota) = self.quota(key) else {
+            return (wanted, None);
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= self.max_buckets && !buckets.contains_key(key) {
+            self.evict(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: quota.burst,
+            updated: now,
+        });
+
+        bucket.refill(quota, now);
+
+        let granted = (bucket.tokens.floor() as u32).min(wanted);
+        bucket.tokens -= granted as f64;
+
+        if granted == wanted {
+            return (granted, None);
+        }
+
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / quota.rate);
+
+        (granted, Some(wait))
+    }
+
+    /// Drops the full buckets, then the least recently used ones while still
+    /// above a tenth below `max_buckets`.
+    fn evict(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        buckets.retain(|key, bucket| {
+            let quota = self.quota(key).expect("bucket without quota");
+            !bucket.is_full(quota, now)
+        });
+
+        let keep = self.max_buckets - self.max_buckets.div_ceil(10);
+
+        if buckets.len() <= keep {
+            return;
+        }
+
+        let mut used: Vec<Instant> = buckets.values().map(|b| b.updated).collect();
+        let (_, cutoff, _) = used.select_nth_unstable(buckets.len() - keep - 1);
+        let cutoff = *cutoff;
+
+        buckets.retain(|key, bucket| key == SHARED || bucket.updated > cutoff);
+    }
+}
+
+fn parse_quotas(input: &str) -> Result<HashMap<String, Quota>> {
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|q| !q.is_empty())
+        .map(parse_quota)
+        .collect()
+}
+
+fn parse_quota(input: &str) -> Result<(String, Quota)> {
+    let mut fields = input.split(';');
+
+    let (key, rate) = fields
+        .next()
+        .and_then(|f| f.split_once('='))
+        .ok_or_else(|| anyhow!("Invalid quota {input:?}"))?;
+
+    let rate: f64 = rate.trim().parse()?;
+    let mut burst = rate;
+
+    for field in fields {
+        match field.trim().split_once('=') {
+            Some(("burst", value)) => burst = value.parse()?,
+            _ => return Err(anyhow!("Invalid quota field {field:?}")),
+        }
+    }
+
+    Ok((key.trim().to_string(), Quota::new(rate, burst)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl RateLimiter {
+        fn check(&self, key: &str, now: Instant) -> Result<(), Duration> {
+            match self.take(key, 1, now) {
+                (1, _) => Ok(()),
+                (_, wait) => Err(wait.unwrap_or_default()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let known = ["a", "b", "c"].map(String::from).into();
+        let limiter = RateLimiter::new(Some(Quota::new(10.0, 3.0).unwrap()), HashMap::new(), known);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", start), Ok(()));
+        }
+
+        let wait = limiter.check("a", start).expect_err("burst exhausted");
+        assert_eq!(wait, Duration::from_millis(100));
+
+        // a batch gets what is left
+        assert_eq!(limiter.take("c", 2, start), (2, None));
+        assert_eq!(
+            limiter.take("c", 5, start),
+            (1, Some(Duration::from_millis(100)))
+        );
+        assert_eq!(limiter.take("c", 1, start).0, 0);
+
+        // other keys have their own bucket
+        assert_eq!(limiter.check("b", start), Ok(()));
+
+        let later = start + Duration::from_millis(150);
+        assert_eq!(limiter.check("a", later), Ok(()));
+        assert!(limiter.check("a", later).is_err());
+
+        // refills never go past the burst
+        let idle = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", idle), Ok(()));
+        }
+        assert!(limiter.check("a", idle).is_err());
+    }
+
+    #[test]
+    fn test_quotas() {
+        let quotas = parse_quotas("partner=100;burst=200, slow=1").unwrap();
+
+        assert_eq!(quotas["partner"], Quota::new(100.0, 200.0).unwrap());
+        assert_eq!(quotas["slow"], Quota::new(1.0, 1.0).unwrap());
+
+        assert!(parse_quotas("bad=0").is_err());
+        assert!(parse_quotas("bad=1;size=2").is_err());
+
+        // without a default only keys with a quota are limited
+        let limiter = RateLimiter::new(None, quotas, HashSet::new());
+        let now = Instant::now();
+
+        assert_eq!(limiter.check("slow", now), Ok(()));
+        assert_eq!(limiter.check("slow", now), Err(Duration::from_secs(1)));
+
+        for _ in 0..10 {
+            assert_eq!(limiter.check("anyone", now), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_unknown_keys_share_a_bucket() {
+        let known = ["merchant".to_string()].into();
+        let limiter = RateLimiter::new(Some(Quota::new(1.0, 2.0).unwrap()), HashMap::new(), known);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check("", now), Ok(()));
+        assert_eq!(limiter.check("made-up", now), Ok(()));
+        assert!(limiter.check("another", now).is_err());
+
+        assert_eq!(limiter.check("merchant", now), Ok(()));
+    }
+
+    #[test]
+    fn test_max_buckets() {
+        let known = (0..100).map(|i| i.to_string()).collect();
+        let mut limiter =
+            RateLimiter::new(Some(Quota::new(1.0, 2.0).unwrap()), HashMap::new(), known);
+        limiter.max_buckets = 10;
+
+        let start = Instant::now();
+
+        // every bucket in use, the oldest are dropped
+        for i in 0..100 {
+            let now = start + Duration::from_millis(i);
+            assert_eq!(limiter.check(&i.to_string(), now), Ok(()));
+
+            assert!(limiter.buckets.lock().unwrap().len() <= 10);
+        }
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.contains_key("99"));
+        assert!(!buckets.contains_key("0"));
+    }
+}
@@ -0,0 +1,54 @@
+use anyhow::Result;
+use tokio::net::UnixStream;
+
+use crate::{
+    api::summary::Scope,
+    data::{self, Currency},
+    db::RefundError,
+    worker::{Context, PaymentRef},
+};
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub enum RefundResult {
+    Refunded {
+        processor: String,
+        /// Cents.
+        amount: u64,
+        currency: Currency,
+    },
+    NotFound,
+    AlreadyRefunded,
+    /// The processor did not accept the refund, it can be tried again.
+    Failed(String),
+}
+
+pub(super) async fn refund(socket: &mut UnixStream, ctx: &Context, req: PaymentRef) -> Result<()> {
+    let result = match data::uuid_to_u128(&req.correlation_id) {
+        Some(id) => process(ctx, id, &req.scope).await,
+        None => RefundResult::NotFound,
+    };
+
+    tracing::info!(correlation_id = req.correlation_id, ?result, "refund");
+
+    data::send_large(result, socket).await
+}
+
+async fn process(ctx: &Context, id: u128, scope: &Scope) -> RefundResult {
+    let payment = match ctx.store.start_refund(id, scope) {
+        Ok(payment) => payment,
+        Err(RefundError::NotFound) => return RefundResult::NotFound,
+        Err(RefundError::AlreadyRefunded) => return RefundResult::AlreadyRefunded,
+    };
+
+    match ctx.manager.refund(payment).await {
+        Ok(()) => RefundResult::Refunded {
+            processor: ctx.store.processor_name(payment.processor_id).to_string(),
+            amount: payment.amount,
+            currency: payment.currency,
+        },
+        Err(err) => {
+            ctx.store.cancel_refund(id);
+            RefundResult::Failed(err.to_string())
+        }
+    }
+}
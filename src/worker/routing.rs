@@ -0,0 +1,370 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Result, anyhow};
+
+use crate::{env_or, worker::estimator::Estimate};
+
+/// Point-in-time view of a processor used to take a routing decision.
+pub struct Candidate {
+    pub fee: f64,
+    pub weight: u32,
+    pub estimate: Estimate,
+}
+
+pub trait RoutingStrategy: Send + Sync {
+    /// Returns the index of the candidate that should receive the next payment.
+    fn choose(&self, candidates: &[Candidate]) -> usize;
+}
+
+pub fn from_env() -> Result<Box<dyn RoutingStrategy>> {
+    let micros_cutout = env_or("PROCESSOR_CUTOUT", 100_000); //100ms
+    let latency_cost = env_or("ROUTING_LATENCY_COST", 0.1);
+    // a fraction of the cutouts
+    let hysteresis = env_or("ROUTING_HYSTERESIS", 0.2);
+    // percentage points of profit
+    let fee_hysteresis = env_or("ROUTING_FEE_HYSTERESIS", 0.2);
+
+    let strategy = std::env::var("ROUTING_STRATEGY").unwrap_or("latency".to_string());
+
+    match strategy.as_str() {
+        "latency" => Ok(Box::new(LatencyCutout::new(micros_cutout, hysteresis))),
+        "fee" => Ok(Box::new(FeeAware::new(latency_cost, fee_hysteresis))),
+        _ => Err(anyhow!("Invalid routing strategy {strategy:?}")),
+    }
+}
+
+/// Failure rate above which a processor is treated as down.
+const MAX_FAILURE_RATE: f64 = 0.5;
+
+/// Picks the cheapest processor among the ones whose p99 is within
+/// `micros_cutout` of the fastest, splitting ties by weight.
+///
+/// Processors picked in the previous decision get a `hysteresis` wider
+/// cutout, so a p99 hovering around the limit does not flap the routing.
+pub struct LatencyCutout {
+    micros_cutout: u64,
+    hysteresis: f64,
+    previous: AtomicU64,
+    round_robin: AtomicU64,
+}
+
+impl LatencyCutout {
+    pub fn new(micros_cutout: u64, hysteresis: f64) -> Self {
+        Self {
+            micros_cutout,
+            hysteresis,
+            previous: AtomicU64::new(0),
+            round_robin: AtomicU64::new(0),
+        }
+    }
+
+    fn latency(&self, c: &Candidate, sticky: bool) -> u64 {
+        let max_failure_rate = match sticky {
+            true => MAX_FAILURE_RATE * (1.0 + self.hysteresis),
+            false => MAX_FAILURE_RATE,
+        };
+
+        match c.estimate.failure_rate > max_failure_rate {
+            true => u64::MAX,
+            false => c.estimate.p99,
+        }
+    }
+}
+
+impl RoutingStrategy for LatencyCutout {
+    fn choose(&self, candidates: &[Candidate]) -> usize {
+        let previous = self.previous.load(Ordering::Relaxed);
+
+        let fastest = candidates
+            .iter()
+            .map(|c| self.latency(c, false))
+            .min()
+            .unwrap_or_default();
+
+        let sticky_cutout = self.micros_cutout as f64 * (1.0 + self.hysteresis);
+
+        let within_cutout = |i: usize, c: &Candidate| {
+            let sticky = was_picked(previous, i);
+
+            let cutout = match sticky {
+                true => sticky_cutout as u64,
+                false => self.micros_cutout,
+            };
+
+            self.latency(c, sticky) <= fastest.saturating_add(cutout)
+        };
+
+        let fee = candidates
+            .iter()
+            .enumerate()
+            .filter(|(i, c)| within_cutout(*i, c))
+            .map(|(_, c)| c.fee)
+            .min_by(f64::total_cmp)
+            .unwrap_or_default();
+
+        let eligible = |i: usize, c: &Candidate| within_cutout(i, c) && c.fee == fee;
+
+        remember(&self.previous, candidates, eligible);
+
+        weighted_pick(&self.round_robin, candidates, eligible)
+    }
+}
+
+/// Picks the processor with the best expected profit per payment: the share
+/// of the amount kept after the fee, weighted by the chance of success and
+/// discounted by `latency_cost` per second of p50 latency.
+///
+/// Processors picked in the previous decision are kept while their profit
+/// stays within `hysteresis` percentage points of the best one.
+pub struct FeeAware {
+    latency_cost: f64,
+    hysteresis: f64,
+    previous: AtomicU64,
+    round_robin: AtomicU64,
+}
+
+impl FeeAware {
+    pub fn new(latency_cost: f64, hysteresis: f64) -> Self {
+        Self {
+            latency_cost,
+            hysteresis,
+            previous: AtomicU64::new(0),
+            round_robin: AtomicU64::new(0),
+        }
+    }
+
+    fn profit(&self, c: &Candidate) -> f64 {
+        let latency_secs = c.estimate.p50 as f64 / 1_000_000.0;
+        let success_rate = 1.0 - c.estimate.failure_rate;
+
+        success_rate * (1.0 - c.fee) - latency_secs * self.latency_cost
+    }
+}
+
+impl RoutingStrategy for FeeAware {
+    fn choose(&self, candidates: &[Candidate]) -> usize {
+        let previous = self.previous.load(Ordering::Relaxed);
+        let margin = self.hysteresis / 100.0;
+
+        let best = candidates
+            .iter()
+            .map(|c| self.profit(c))
+            .max_by(f64::total_cmp)
+            .unwrap_or_default();
+
+        let sticky =
+            |i: usize, c: &Candidate| was_picked(previous, i) && self.profit(c) >= best - margin;
+
+        let keep = candidates.iter().enumerate().any(|(i, c)| sticky(i, c));
+
+        let eligible = |i: usize, c: &Candidate| match keep {
+            true => sticky(i, c),
+            false => self.profit(c) == best,
+        };
+
+        remember(&self.previous, candidates, eligible);
+
+        weighted_pick(&self.round_robin, candidates, eligible)
+    }
+}
+
+fn was_picked(previous: u64, i: usize) -> bool {
+    i < 64 && previous & (1 << i) != 0
+}
+
+fn remember(
+    previous: &AtomicU64,
+    candidates: &[Candidate],
+    eligible: impl Fn(usize, &Candidate) -> bool,
+) {
+    let picked = candidates
+        .iter()
+        .enumerate()
+        .take(64)
+        .filter(|(i, c)| eligible(*i, c))
+        .fold(0, |acc, (i, _)| acc | 1 << i);
+
+    previous.store(picked, Ordering::Relaxed);
+}
+
+fn weighted_pick(
+    counter: &AtomicU64,
+    candidates: &[Candidate],
+    eligible: impl Fn(usize, &Candidate) -> bool,
+) -> usize {
+    let eligible = || {
+        candidates
+            .iter()
+            .enumerate()
+            .filter(|(i, c)| eligible(*i, c))
+    };
+
+    let total_weight: u64 = eligible().map(|(_, c)| c.weight as u64).sum();
+
+    let first = eligible().next().map(|(i, _)| i).unwrap_or_default();
+
+    if total_weight == 0 {
+        return first;
+    }
+
+    let mut n = counter.fetch_add(1, Ordering::Relaxed) % total_weight;
+
+    for (i, c) in eligible() {
+        if n < c.weight as u64 {
+            return i;
+        }
+
+        n -= c.weight as u64;
+    }
+
+    first
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::estimator::Estimator;
+
+    fn candidate(fee: f64, latency: u64, failure_rate: f64) -> Candidate {
+        Candidate {
+            fee,
+            weight: 1,
+            estimate: Estimate {
+                ewma: latency,
+                p50: latency,
+                p99: latency,
+                failure_rate,
+            },
+        }
+    }
+
+    #[test]
+    fn test_latency_cutout() {
+        let strategy = LatencyCutout::new(100_000, 0.0);
+
+        let healthy = [candidate(0.05, 50_000, 0.0), candidate(0.15, 10_000, 0.0)];
+        assert_eq!(strategy.choose(&healthy), 0);
+
+        let slow = [candidate(0.05, 500_000, 0.0), candidate(0.15, 10_000, 0.0)];
+        assert_eq!(strategy.choose(&slow), 1);
+
+        let failing = [candidate(0.05, 10_000, 0.9), candidate(0.15, 10_000, 0.0)];
+        assert_eq!(strategy.choose(&failing), 1);
+    }
+
+    #[test]
+    fn test_fee_aware() {
+        let strategy = FeeAware::new(0.1, 0.0);
+
+        let healthy = [candidate(0.05, 50_000, 0.0), candidate(0.15, 10_000, 0.0)];
+        assert_eq!(strategy.choose(&healthy), 0);
+
+        let failing = [candidate(0.05, 10_000, 0.5), candidate(0.15, 10_000, 0.01)];
+        assert_eq!(strategy.choose(&failing), 1);
+
+        let slow = [
+            candidate(0.05, 2_000_000, 0.0),
+            candidate(0.15, 10_000, 0.0),
+        ];
+        assert_eq!(strategy.choose(&slow), 1);
+    }
+
+    #[test]
+    fn test_weighted_ties() {
+        let strategy = LatencyCutout::new(100_000, 0.2);
+
+        let mut candidates = [candidate(0.05, 0, 0.0), candidate(0.05, 0, 0.0)];
+        candidates[1].weight = 3;
+
+        let picks: Vec<_> = (0..4).map(|_| strategy.choose(&candidates)).collect();
+
+        assert_eq!(picks, [0, 1, 1, 1]);
+    }
+
+    /// Feeds the same latency trace for `default` through an estimator and
+    /// returns the processor picked after each sample.
+    fn replay(strategy: &dyn RoutingStrategy, default_trace: &[u64]) -> Vec<usize> {
+        let default = Estimator::new(0.1);
+        let fallback = Estimator::new(0.1);
+
+        default_trace
+            .iter()
+            .map(|latency| {
+                default.record(*latency, true);
+                fallback.record(10_000, true);
+
+                let candidates = [
+                    Candidate {
+                        fee: 0.05,
+                        weight: 1,
+                        estimate: default.estimate(),
+                    },
+                    Candidate {
+                        fee: 0.15,
+                        weight: 1,
+                        estimate: fallback.estimate(),
+                    },
+                ];
+
+                strategy.choose(&candidates)
+            })
+            .collect()
+    }
+
+    fn switches(picks: &[usize]) -> usize {
+        picks.windows(2).filter(|w| w[0] != w[1]).count()
+    }
+
+    #[test]
+    fn test_latency_cutout_trace() {
+        let strategy = LatencyCutout::new(100_000, 0.2);
+
+        // a lone outlier does not move traffic away from default
+        let mut trace = vec![20_000; 512];
+        trace[300] = 3_000_000;
+        let picks = replay(&strategy, &trace);
+        assert!(picks.iter().all(|p| *p == 0));
+
+        // sustained slowness switches exactly once
+        let trace: Vec<_> = (0..512)
+            .map(|i| if i < 256 { 20_000 } else { 400_000 })
+            .collect();
+        let picks = replay(&strategy, &trace);
+        assert_eq!(switches(&picks), 1);
+        assert_eq!(picks.last(), Some(&1));
+    }
+
+    #[test]
+    fn test_latency_cutout_hysteresis() {
+        let flaky = LatencyCutout::new(100_000, 0.0);
+        let stable = LatencyCutout::new(100_000, 0.2);
+
+        // p99 oscillating around the cutout: 105ms and 125ms against a 10ms fallback
+        let trace: Vec<_> = (0..2048)
+            .map(|i| if (i / 256) % 2 == 0 { 105_000 } else { 125_000 })
+            .collect();
+
+        let flaky = replay(&flaky, &trace);
+        let stable = replay(&stable, &trace);
+
+        assert!(switches(&flaky) > 4);
+        assert_eq!(switches(&stable), 0);
+    }
+
+    #[test]
+    fn test_fee_aware_hysteresis() {
+        let flaky = FeeAware::new(1.0, 0.0);
+        let stable = FeeAware::new(1.0, 2.0);
+
+        // p50 of default oscillating around the 110ms break-even with fallback
+        let trace: Vec<_> = (0..2048)
+            .map(|i| if (i / 256) % 2 == 0 { 105_000 } else { 115_000 })
+            .collect();
+
+        let flaky = replay(&flaky, &trace);
+        let stable = replay(&stable, &trace);
+
+        assert!(switches(&flaky) > 4);
+        assert_eq!(switches(&stable), 0);
+    }
+}
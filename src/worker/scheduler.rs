@@ -0,0 +1,182 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+
+use chrono::Utc;
+use tokio::sync::Notify;
+
+use crate::{api::payment, worker::Sender};
+
+/// Holds future-dated payments until they are due, then hands them to the
+/// dispatcher queue.
+pub struct Scheduler {
+    state: Mutex<State>,
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    /// `(scheduled_at, correlation_id)`, cancelled payments are skipped when
+    /// they reach the top.
+    due: BinaryHeap<Reverse<(i64, String)>>,
+    payments: HashMap<String, payment::Request>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::default(),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Keeps `req` until its `scheduled_at`, replacing an earlier schedule of
+    /// the same payment.
+    pub fn schedule(&self, mut req: payment::Request) {
+        let at = *req.scheduled_at.get_or_insert(0);
+
+        {
+            let mut state = self.state.lock().expect("scheduler lock");
+
+            state.due.push(Reverse((at, req.correlation_id.clone())));
+            state.payments.insert(req.correlation_id.clone(), req);
+        }
+
+        self.changed.notify_one();
+    }
+
+    pub fn get(&self, correlation_id: &str) -> Option<payment::Request> {
+        let state = self.state.lock().expect("scheduler lock");
+
+        state.payments.get(correlation_id).cloned()
+    }
+
+    pub fn cancel(&self, correlation_id: &str) -> Option<payment::Request> {
+        let mut state = self.state.lock().expect("scheduler lock");
+
+        state.payments.remove(correlation_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().expect("scheduler lock").payments.len()
+    }
+
+    /// Removes the payments due at `now`, also returning when the next one is.
+    fn take_due(&self, now: i64) -> (Vec<payment::Request>, Option<i64>) {
+        let mut state = self.state.lock().expect("scheduler lock");
+        let mut due = Vec::new();
+
+        while let Some(Reverse((at, id))) = state.due.peek() {
+            let current = state.payments.get(id).map(|req| req.scheduled_at);
+
+            // cancelled, or rescheduled to another time
+            if current != Some(Some(*at)) {
+                state.due.pop();
+                continue;
+            }
+
+            if *at > now {
+                return (due, Some(*at));
+            }
+
+            let id = id.clone();
+            state.due.pop();
+            due.extend(state.payments.remove(&id));
+        }
+
+        (due, None)
+    }
+
+    /// Releases payments into `tx` as they become due.
+    pub async fn run(&self, tx: Sender) {
+        loop {
+            let now = Utc::now().timestamp_micros();
+            let (due, next) = self.take_due(now);
+
+            for req in due {
+                tracing::debug!(req.correlation_id, "releasing scheduled payment");
+
+                if tx.send_async(req).await.is_err() {
+                    return;
+                }
+            }
+
+            match next {
+                Some(at) => {
+                    let wait = Duration::from_micros((at - now).max(0) as u64);
+
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {}
+                        _ = self.changed.notified() => {}
+                    }
+                }
+                None => self.changed.notified().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::testing;
+
+    fn request(i: usize, at: i64) -> payment::Request {
+        payment::Request {
+            scheduled_at: Some(at),
+            ..testing::request(i)
+        }
+    }
+
+    #[test]
+    fn test_take_due() {
+        let scheduler = Scheduler::new();
+
+        scheduler.schedule(request(1, 30));
+        scheduler.schedule(request(2, 10));
+        scheduler.schedule(request(3, 20));
+        scheduler.schedule(request(4, 40));
+
+        assert!(scheduler.cancel(&request(3, 20).correlation_id).is_some());
+
+        // rescheduled later, the first entry is stale
+        scheduler.schedule(request(2, 50));
+
+        let (due, next) = scheduler.take_due(35);
+        let ids: Vec<_> = due.iter().map(|r| r.correlation_id.clone()).collect();
+        assert_eq!(ids, [request(1, 30).correlation_id]);
+        assert_eq!(next, Some(40));
+
+        assert_eq!(scheduler.len(), 2);
+
+        let (due, next) = scheduler.take_due(100);
+        assert_eq!(due.len(), 2);
+        assert_eq!(next, None);
+        assert!(scheduler.get(&request(2, 50).correlation_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let scheduler = std::sync::Arc::new(Scheduler::new());
+        let (tx, rx) = flume::unbounded();
+
+        tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.run(tx).await }
+        });
+
+        let at = Utc::now().timestamp_micros() + 50_000;
+        scheduler.schedule(request(1, at));
+
+        let req = tokio::time::timeout(Duration::from_secs(1), rx.recv_async())
+            .await
+            .expect("released in time")
+            .expect("request");
+
+        assert_eq!(req.correlation_id, request(1, at).correlation_id);
+        assert!(Utc::now().timestamp_micros() >= at);
+    }
+}
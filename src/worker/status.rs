@@ -0,0 +1,95 @@
+use anyhow::Result;
+use tokio::net::UnixStream;
+
+use crate::{
+    data::{self, Currency},
+    worker::{Context, PaymentRef},
+};
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub enum PaymentStatus {
+    Scheduled {
+        scheduled_at: i64,
+        /// Cents.
+        amount: u64,
+        currency: Currency,
+    },
+    /// Accepted, waiting for a processor.
+    Pending,
+    Processed {
+        processor: String,
+        /// Cents.
+        amount: u64,
+        currency: Currency,
+        requested_at: i64,
+        refunded: bool,
+    },
+    NotFound,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub enum CancelResult {
+    Cancelled,
+    /// Already released to a processor.
+    NotScheduled,
+    NotFound,
+}
+
+pub(super) async fn status(socket: &mut UnixStream, ctx: &Context, req: PaymentRef) -> Result<()> {
+    let status = find(ctx, &req);
+
+    data::send_large(status, socket).await
+}
+
+fn find(ctx: &Context, req: &PaymentRef) -> PaymentStatus {
+    let scheduled = ctx
+        .scheduler
+        .get(&req.correlation_id)
+        // a failed payment waiting for its retry is still pending
+        .filter(|p| p.attempts == 0 && req.scope.allows(p.merchant.as_deref()));
+
+    if let Some(payment) = scheduled {
+        return PaymentStatus::Scheduled {
+            scheduled_at: payment.scheduled_at.unwrap_or_default(),
+            amount: payment.amount,
+            currency: payment.currency,
+        };
+    }
+
+    if ctx.pending.contains(&req.correlation_id, &req.scope) {
+        return PaymentStatus::Pending;
+    }
+
+    let processed =
+        data::uuid_to_u128(&req.correlation_id).and_then(|id| ctx.store.find(id, &req.scope));
+
+    match processed {
+        Some((payment, refunded)) => PaymentStatus::Processed {
+            processor: ctx.store.processor_name(payment.processor_id).to_string(),
+            amount: payment.amount,
+            currency: payment.currency,
+            requested_at: payment.requested_at,
+            refunded,
+        },
+        None => PaymentStatus::NotFound,
+    }
+}
+
+pub(super) async fn cancel(socket: &mut UnixStream, ctx: &Context, req: PaymentRef) -> Result<()> {
+    let result = match find(ctx, &req) {
+        PaymentStatus::Scheduled { .. } => match ctx.scheduler.cancel(&req.correlation_id) {
+            Some(_) => {
+                ctx.pending.complete(&req.correlation_id)?;
+                CancelResult::Cancelled
+            }
+            // released between the lookup and now
+            None => CancelResult::NotScheduled,
+        },
+        PaymentStatus::NotFound => CancelResult::NotFound,
+        _ => CancelResult::NotScheduled,
+    };
+
+    tracing::info!(correlation_id = req.correlation_id, ?result, "cancel");
+
+    data::send_large(result, socket).await
+}
@@ -1,32 +1,334 @@
-use std::io::{Cursor, Write};
+use std::{io::Write, time::Duration};
 
 use anyhow::Result;
-use tokio::{io::AsyncWriteExt, net::UnixStream};
+use tokio::{net::UnixStream, sync::broadcast::error::RecvError};
 
-use crate::{api::summary::Summary, db};
+use crate::{
+    api::summary::{ProcessedData, Query, Scope, Summary, Update},
+    data::{self, FramedStream},
+    db::{self, Change},
+    shutdown::Shutdown,
+};
 
-pub async fn process(socket: &mut UnixStream, store: &db::Store, query: (i64, i64)) -> Result<()> {
+pub async fn process(socket: &mut UnixStream, store: &db::Store, query: Query) -> Result<()> {
     tracing::trace!("handling get_summary");
 
-    let summary = store.get(query).await;
+    let summaries = store.get(query.range, &query.scope).await;
 
-    let mut buf = [0u8; 128];
+    let mut buf = Vec::with_capacity(128);
 
-    let n = build_payload(&mut buf, summary)?;
+    build_payload(&mut buf, &summaries)?;
 
-    socket.write_all(&buf[..n]).await?;
+    data::send_large(buf, socket).await
+}
+
+/// Sends every total within `scope`, then what changed since the previous
+/// update at most once per `interval`, until the API closes the connection.
+pub async fn stream(
+    stream: &mut FramedStream<UnixStream>,
+    store: &db::Store,
+    scope: Scope,
+    interval: Duration,
+    shutdown: &mut Shutdown,
+) -> Result<()> {
+    let mut tick = tokio::time::interval(interval);
+
+    'snapshot: loop {
+        let (totals, mut changes) = store.subscribe(&scope).await;
+
+        let snapshot = Update::Snapshot {
+            processors: store.processors().to_vec(),
+            totals,
+        };
+        data::send_large(snapshot, stream.inner()).await?;
+
+        let mut delta = Vec::new();
+
+        loop {
+            tokio::select! {
+                change = changes.recv() => match change {
+                    Ok(Change::Inserted(payment)) => {
+                        let merchant = store.merchant_name(payment.merchant_id);
+
+                        if scope.allows(merchant.as_deref()) {
+                            store.accumulate(&mut delta, &payment);
+                        }
+                    }
+                    // totals changed at once, or changes were missed
+                    Ok(Change::Reset) | Err(RecvError::Lagged(_)) => continue 'snapshot,
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = tick.tick() => {
+                    if !delta.is_empty() {
+                        let update = Update::Delta(std::mem::take(&mut delta));
+                        data::send_large(update, stream.inner()).await?;
+                    }
+                }
+                n = stream.read() => {
+                    if n? == 0 {
+                        return Ok(());
+                    }
+                }
+                _ = shutdown.wait() => return Ok(()),
+            }
+        }
+    }
+}
+
+/// The processors' totals when at most one currency has payments, otherwise
+/// one such object per currency code.
+pub fn build_payload(writer: &mut impl Write, summaries: &[Summary]) -> Result<()> {
+    let mut used = summaries.iter().filter(|summary| {
+        summary
+            .processors
+            .iter()
+            .any(|(_, data)| data.count > 0 || data.refunds > 0)
+    });
+
+    let single = match (used.next(), used.next()) {
+        (Some(summary), None) => Some(summary),
+        (None, _) => summaries.first(),
+        (Some(_), Some(_)) => None,
+    };
+
+    if let Some(summary) = single {
+        return build_processors(writer, summary);
+    }
+
+    write!(writer, "{{")?;
+
+    for (i, summary) in summaries.iter().enumerate() {
+        if i > 0 {
+            write!(writer, ",")?;
+        }
+
+        write!(writer, r#""{}":"#, summary.currency)?;
+        build_processors(writer, summary)?;
+    }
+
+    write!(writer, "}}")?;
+
+    Ok(())
+}
+
+const LEGACY_PROCESSORS: [&str; 2] = ["default", "fallback"];
+
+/// Writes `default` and `fallback` first (zeroed when not configured) to keep
+/// the original two-processor shape, followed by any other processor by name.
+fn build_processors(writer: &mut impl Write, summary: &Summary) -> Result<()> {
+    const EMPTY: ProcessedData = ProcessedData {
+        count: 0,
+        amount: 0.0,
+        refunds: 0,
+        refunded: 0.0,
+        net: 0.0,
+    };
+
+    let legacy = LEGACY_PROCESSORS
+        .iter()
+        .map(|name| (*name, summary.get(name).unwrap_or(&EMPTY)));
+
+    let others = summary
+        .processors
+        .iter()
+        .filter(|(name, _)| !LEGACY_PROCESSORS.contains(&name.as_str()))
+        .map(|(name, data)| (name.as_str(), data));
+
+    write!(writer, "{{")?;
+
+    for (i, (name, data)) in legacy.chain(others).enumerate() {
+        if i > 0 {
+            write!(writer, ",")?;
+        }
+
+        write!(
+            writer,
+            r#"{}:{{"totalRequests":{},"totalAmount":{},"totalRefunds":{},"refundedAmount":{},"netAmount":{}}}"#,
+            serde_json::to_string(name)?,
+            data.count,
+            data.amount,
+            data.refunds,
+            data.refunded,
+            data.net
+        )?;
+    }
+
+    write!(writer, "}}")?;
 
     Ok(())
 }
 
-fn build_payload(buf: &mut [u8], Summary { default, fallback }: Summary) -> Result<usize> {
-    let mut writer = Cursor::new(buf);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::summary::Totals, data::Currency};
+
+    fn totals(count: u64, amount: u64) -> Totals {
+        Totals {
+            count,
+            amount,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_build_payload() {
+        let names = ["fallback", "backup", "default"].map(String::from);
+        let summary = Summary::new(
+            &names,
+            Currency::default(),
+            vec![
+                totals(1, 150),
+                totals(2, 1000),
+                Totals {
+                    refunds: 1,
+                    refunded: 990,
+                    ..totals(3, 1990)
+                },
+            ],
+        );
+
+        let mut buf = Vec::new();
+        build_payload(&mut buf, &[summary]).expect("build payload");
+
+        assert_eq!(
+            std::str::from_utf8(&buf).expect("utf8"),
+            concat!(
+                r#"{"default":{"totalRequests":3,"totalAmount":19.9,"totalRefunds":1,"refundedAmount":9.9,"netAmount":10},"#,
+                r#""fallback":{"totalRequests":1,"totalAmount":1.5,"totalRefunds":0,"refundedAmount":0,"netAmount":1.5},"#,
+                r#""backup":{"totalRequests":2,"totalAmount":10,"totalRefunds":0,"refundedAmount":0,"netAmount":10}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_build_payload_currencies() {
+        let names = ["default", "fallback"].map(String::from);
+
+        let summaries = [
+            Summary::new(
+                &names,
+                "BRL".parse().unwrap(),
+                vec![totals(1, 150), totals(0, 0)],
+            ),
+            Summary::new(
+                &names,
+                "USD".parse().unwrap(),
+                vec![totals(0, 0), totals(2, 1000)],
+            ),
+        ];
+
+        let mut buf = Vec::new();
+        build_payload(&mut buf, &summaries).expect("build payload");
+
+        let payload: serde_json::Value = serde_json::from_slice(&buf).expect("json");
+
+        assert_eq!(payload["BRL"]["default"]["totalRequests"], 1);
+        assert_eq!(payload["BRL"]["fallback"]["totalRequests"], 0);
+        assert_eq!(payload["USD"]["fallback"]["totalRequests"], 2);
+        assert_eq!(payload["USD"]["fallback"]["totalAmount"], 10.0);
+    }
+
+    #[test]
+    fn test_build_payload_one_currency_used() {
+        let names = ["default", "fallback"].map(String::from);
+
+        // ALLOWED_CURRENCIES=BRL,USD with BRL traffic only
+        let summaries = [
+            Summary::new(
+                &names,
+                "BRL".parse().unwrap(),
+                vec![totals(1, 150), totals(2, 1000)],
+            ),
+            Summary::new(
+                &names,
+                "USD".parse().unwrap(),
+                vec![totals(0, 0), totals(0, 0)],
+            ),
+        ];
+
+        let mut buf = Vec::new();
+        build_payload(&mut buf, &summaries).expect("build payload");
+
+        let payload: serde_json::Value = serde_json::from_slice(&buf).expect("json");
+
+        assert_eq!(payload["default"]["totalRequests"], 1);
+        assert_eq!(payload["fallback"]["totalAmount"], 10.0);
+        assert!(payload.get("BRL").is_none());
+
+        // no traffic at all
+        let mut buf = Vec::new();
+        build_payload(&mut buf, &summaries[1..]).expect("build payload");
+
+        let payload: serde_json::Value = serde_json::from_slice(&buf).expect("json");
+        assert_eq!(payload["default"]["totalRequests"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let store = db::Store::new(vec!["default".to_string(), "fallback".to_string()]);
+        let payment = |correlation_id, processor_id, merchant_id| data::Payment {
+            correlation_id,
+            amount: 1000,
+            requested_at: correlation_id as i64,
+            processor_id,
+            merchant_id,
+            currency: Currency::default(),
+            refund: false,
+        };
+
+        store.insert(payment(1, 0, db::NO_MERCHANT)).await;
+
+        let (worker, mut api) = UnixStream::pair().unwrap();
+
+        tokio::spawn({
+            let store = store.clone();
+            let mut worker = FramedStream::new(worker);
+            let mut shutdown = Shutdown::listen();
+            let interval = Duration::from_millis(20);
+
+            async move {
+                stream(
+                    &mut worker,
+                    &store,
+                    Scope::Merchant(None),
+                    interval,
+                    &mut shutdown,
+                )
+                .await
+            }
+        });
+
+        let Update::Snapshot {
+            processors,
+            totals: snapshot,
+        } = data::recv(&mut api).await.unwrap()
+        else {
+            panic!("expected a snapshot");
+        };
+        assert_eq!(processors, ["default", "fallback"]);
+        assert_eq!(snapshot[0].1[0], totals(1, 1000));
+
+        // coalesced into a single delta, other merchants left out
+        let acme = store.merchant_id(Some("acme"));
+        store.insert(payment(2, 1, db::NO_MERCHANT)).await;
+        store.insert(payment(3, 1, db::NO_MERCHANT)).await;
+        store.insert(payment(4, 1, acme)).await;
+
+        let Update::Delta(delta) = data::recv(&mut api).await.unwrap() else {
+            panic!("expected a delta");
+        };
+        assert_eq!(delta[0].1, [Totals::default(), totals(2, 2000)]);
 
-    write!(
-        writer,
-        r#"{{"default":{{"totalRequests":{},"totalAmount":{}}},"fallback":{{"totalRequests":{},"totalAmount":{}}}}}"#,
-        default.count, default.amount, fallback.count, fallback.amount
-    )?;
+        // a purge starts over
+        store.purge(Some((1, 1))).await;
 
-    Ok(writer.position() as usize)
+        let Update::Snapshot {
+            totals: snapshot, ..
+        } = data::recv(&mut api).await.unwrap()
+        else {
+            panic!("expected a snapshot");
+        };
+        assert_eq!(snapshot[0].1, [Totals::default(), totals(2, 2000)]);
+    }
 }
@@ -0,0 +1,66 @@
+//! Fixtures shared by the worker tests.
+
+use std::{sync::Arc, time::Duration};
+
+use reqwest::Client;
+
+use crate::{
+    api::payment,
+    db,
+    worker::{
+        limiter::LimiterConfig,
+        pending::PendingQueue,
+        pp_client::{PaymentsManager, ProcessorConfig},
+        routing::LatencyCutout,
+    },
+};
+
+/// A due R$ 19.90 payment, `i` tells the correlation ids apart.
+pub fn request(i: usize) -> payment::Request {
+    payment::Request {
+        correlation_id: format!("00000000-0000-4000-8000-{i:012}"),
+        amount: 1990,
+        currency: Default::default(),
+        merchant: None,
+        scheduled_at: None,
+        callback_url: None,
+        attempts: 0,
+        requested_at: None,
+        processor: None,
+    }
+}
+
+pub fn processor(name: &str, url: String) -> ProcessorConfig {
+    ProcessorConfig {
+        name: name.to_string(),
+        url,
+        fee: Some(0.05),
+        weight: 1,
+    }
+}
+
+/// Up to 4 payments in flight per processor, routed by latency.
+pub fn manager(
+    processors: &[ProcessorConfig],
+    store: db::Store,
+    pending: Arc<PendingQueue>,
+    client: &Client,
+) -> Arc<PaymentsManager> {
+    let limits = LimiterConfig {
+        initial: 4,
+        min: 1,
+        max: 4,
+        target: Duration::from_secs(1),
+        backoff: 0.9,
+    };
+
+    PaymentsManager::new(
+        processors,
+        Box::new(LatencyCutout::new(100_000, 0.2)),
+        limits,
+        0.1,
+        store,
+        pending,
+        client,
+    )
+}
@@ -0,0 +1,608 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{
+    Client, Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect,
+};
+use sha2::Sha256;
+
+use crate::{api::payment, data::cents, env_or};
+
+/// Longest wait between two deliveries of the same webhook.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A processor took the payment.
+    Processed { processor: String },
+    /// The payment was given up on after `MAX_ATTEMPTS` tries.
+    Failed,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Processed { .. } => "payment.processed",
+            Event::Failed => "payment.failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub secret: String,
+    /// For payments without a callback nor a merchant URL.
+    pub default_url: Option<String>,
+    pub merchant_urls: HashMap<String, String>,
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    /// Hosts webhooks may go to, any when empty.
+    pub allowed_hosts: Vec<String>,
+    /// Whether loopback and private addresses are reachable.
+    pub allow_private: bool,
+    pub connect_timeout: Duration,
+    pub timeout: Duration,
+}
+
+impl WebhookConfig {
+    /// `None` unless `WEBHOOK_SECRET` is set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(secret) = std::env::var("WEBHOOK_SECRET") else {
+            return Ok(None);
+        };
+
+        let merchant_urls = match std::env::var("WEBHOOK_URLS") {
+            Ok(urls) => parse_urls(&urls)?,
+            Err(_) => HashMap::new(),
+        };
+
+        let allowed_hosts = std::env::var("WEBHOOK_ALLOWED_HOSTS").unwrap_or_default();
+        let millis = |name, default| Duration::from_millis(env_or(name, default));
+
+        Ok(Some(Self {
+            secret,
+            default_url: std::env::var("WEBHOOK_URL").ok(),
+            merchant_urls,
+            max_attempts: env_or("WEBHOOK_MAX_ATTEMPTS", 5),
+            backoff: millis("WEBHOOK_BACKOFF_MS", 500),
+            allowed_hosts: allowed_hosts
+                .split(',')
+                .map(|host| host.trim().to_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect(),
+            allow_private: std::env::var("WEBHOOK_ALLOW_PRIVATE").is_ok_and(|v| v == "true"),
+            connect_timeout: millis("WEBHOOK_CONNECT_TIMEOUT_MS", 1_000),
+            timeout: millis("WEBHOOK_TIMEOUT_MS", 5_000),
+        }))
+    }
+}
+
+fn parse_urls(input: &str) -> Result<HashMap<String, String>> {
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|u| !u.is_empty())
+        .map(|u| {
+            u.split_once('=')
+                .map(|(merchant, url)| (merchant.trim().to_string(), url.trim().to_string()))
+                .ok_or_else(|| anyhow!("Invalid webhook URL {u:?}"))
+        })
+        .collect()
+}
+
+struct Delivery {
+    url: String,
+    body: String,
+    correlation_id: String,
+    attempts: u32,
+}
+
+/// Posts signed payment outcomes, retrying with exponential backoff.
+pub struct Webhooks {
+    inner: Option<Inner>,
+}
+
+struct Inner {
+    config: WebhookConfig,
+    client: Client,
+    tx: flume::Sender<Delivery>,
+}
+
+impl Webhooks {
+    pub fn disabled() -> Arc<Self> {
+        Arc::new(Self { inner: None })
+    }
+
+    /// Starts the delivery task, which lives as long as the runtime.
+    pub fn start(config: WebhookConfig) -> Result<Arc<Self>> {
+        let (tx, rx) = flume::unbounded();
+
+        // apart from the processors' client, so slow receivers never hold
+        // its connections, and never following redirects
+        let client = Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.timeout)
+            .redirect(redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicResolver {
+                allow_private: config.allow_private,
+            }))
+            .build()?;
+
+        let webhooks = Arc::new(Self {
+            inner: Some(Inner { config, client, tx }),
+        });
+
+        tokio::spawn({
+            let webhooks = webhooks.clone();
+            async move { webhooks.run(rx).await }
+        });
+
+        Ok(webhooks)
+    }
+
+    /// Queues the webhook of `req`, if it has somewhere to go.
+    pub fn notify(&self, req: &payment::Request, event: Event) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+
+        let Some(url) = inner.url(req) else {
+            return;
+        };
+
+        if let Err(err) = inner.check(url) {
+            tracing::warn!(?err, req.correlation_id, "webhook refused");
+            metrics::counter!("webhook.refused").increment(1);
+            return;
+        }
+
+        let delivery = Delivery {
+            url: url.to_string(),
+            body: body(req, &event),
+            correlation_id: req.correlation_id.clone(),
+            attempts: 0,
+        };
+
+        metrics::counter!("webhook.queued", "event" => event.name()).increment(1);
+
+        // the receiver is owned by the delivery task of this same value
+        inner.tx.send(delivery).ok();
+    }
+
+    async fn run(self: Arc<Self>, rx: flume::Receiver<Delivery>) {
+        while let Ok(delivery) = rx.recv_async().await {
+            let webhooks = self.clone();
+            tokio::spawn(async move { webhooks.deliver(delivery).await });
+        }
+    }
+
+    async fn deliver(&self, mut delivery: Delivery) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+
+        let err = match inner.post(&delivery).await {
+            Ok(()) => {
+                metrics::counter!("webhook.delivered").increment(1);
+                return;
+            }
+            Err(err) => err,
+        };
+
+        delivery.attempts += 1;
+
+        if delivery.attempts >= inner.config.max_attempts {
+            tracing::warn!(
+                ?err,
+                delivery.correlation_id,
+                delivery.attempts,
+                "webhook dropped"
+            );
+            metrics::counter!("webhook.dead_letter").increment(1);
+            return;
+        }
+
+        tracing::debug!(?err, delivery.correlation_id, "webhook failed, retrying");
+        metrics::counter!("webhook.retry").increment(1);
+
+        tokio::time::sleep(backoff(inner.config.backoff, delivery.attempts)).await;
+
+        inner.tx.send_async(delivery).await.ok();
+    }
+}
+
+impl Inner {
+    /// The payment's own URL, then its merchant's, then the default one.
+    fn url<'a>(&'a self, req: &'a payment::Request) -> Option<&'a str> {
+        let merchant = || {
+            req.merchant
+                .as_ref()
+                .and_then(|merchant| self.config.merchant_urls.get(merchant))
+        };
+
+        req.callback_url
+            .as_ref()
+            .or_else(merchant)
+            .or(self.config.default_url.as_ref())
+            .map(String::as_str)
+    }
+
+    /// Refuses hosts outside `allowed_hosts` and private addresses; names
+    /// are checked again when resolved.
+    fn check(&self, url: &str) -> Result<()> {
+        let url = Url::parse(url)?;
+
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(anyhow!("scheme {} not allowed", url.scheme()));
+        }
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("URL without a host"))?;
+        let allowed = &self.config.allowed_hosts;
+
+        if !allowed.is_empty() && !allowed.iter().any(|h| h == host) {
+            return Err(anyhow!("host {host} not allowed"));
+        }
+
+        let ip = host.trim_start_matches('[').trim_end_matches(']').parse();
+
+        match ip {
+            Ok(ip) if !self.config.allow_private && !is_public(ip) => {
+                Err(anyhow!("address {ip} not allowed"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn post(&self, delivery: &Delivery) -> Result<()> {
+        let timestamp = Utc::now().timestamp().to_string();
+        let signature = sign(&self.config.secret, &timestamp, &delivery.body);
+
+        let res = self
+            .client
+            .post(&delivery.url)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Timestamp", &timestamp)
+            .header("X-Webhook-Signature", format!("sha256={signature}"))
+            .body(delivery.body.clone())
+            .send()
+            .await?;
+
+        match res.status().is_success() {
+            true => Ok(()),
+            false => Err(anyhow!("{}", res.status())),
+        }
+    }
+}
+
+fn body(req: &payment::Request, event: &Event) -> String {
+    let processor = match event {
+        Event::Processed { processor } => Some(processor.as_str()),
+        Event::Failed => None,
+    };
+
+    serde_json::json!({
+        "event": event.name(),
+        "correlationId": req.correlation_id,
+        "amount": cents::to_decimal(req.amount),
+        "currency": req.currency.as_str(),
+        "merchant": req.merchant,
+        "processor": processor,
+    })
+    .to_string()
+}
+
+/// Hex HMAC-SHA256 of `{timestamp}.{body}`, so a captured body can not be
+/// replayed under a newer timestamp.
+pub fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key size");
+
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Resolves webhook hosts to public addresses only, so not even DNS can point
+/// a callback at the internal network.
+struct PublicResolver {
+    allow_private: bool,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allow_private = self.allow_private;
+
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| allow_private || is_public(addr.ip()))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(anyhow!("{} has no public address", name.as_str()).into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            // 100.64.0.0/10, carrier-grade NAT
+            let shared = a == 100 && b & 0xc0 == 64;
+
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || shared)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// `base` doubled after each failed attempt, up to `MAX_BACKOFF`.
+fn backoff(base: Duration, attempts: u32) -> Duration {
+    base.saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::testing;
+    use std::sync::Mutex;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    type Received = Arc<Mutex<Vec<(String, String, String)>>>;
+
+    /// HTTP sink answering 500 to the first `failures` requests and 200
+    /// afterwards, keeping the timestamp, signature and body of each one.
+    async fn spawn_sink(failures: usize) -> (String, Received) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let received = Received::default();
+
+        tokio::spawn({
+            let received = received.clone();
+
+            async move {
+                loop {
+                    let (mut socket, _) = listener.accept().await.expect("accept");
+                    let received = received.clone();
+
+                    tokio::spawn(async move {
+                        let mut buf = vec![0u8; 4096];
+                        let mut len = 0;
+
+                        // reqwest sends headers and body apart
+                        let (header_end, body_len) = loop {
+                            let n = socket.read(&mut buf[len..]).await.expect("read");
+                            if n == 0 {
+                                return;
+                            }
+                            len += n;
+
+                            let mut headers = [httparse::EMPTY_HEADER; 16];
+                            let mut req = httparse::Request::new(&mut headers);
+
+                            if let Ok(httparse::Status::Complete(end)) = req.parse(&buf[..len]) {
+                                let body_len: usize = req
+                                    .headers
+                                    .iter()
+                                    .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+                                    .and_then(|h| std::str::from_utf8(h.value).ok()?.parse().ok())
+                                    .expect("content-length");
+
+                                if len >= end + body_len {
+                                    break (end, body_len);
+                                }
+                            }
+                        };
+
+                        let text = String::from_utf8_lossy(&buf[..header_end]).to_lowercase();
+                        let header = |name: &str| {
+                            text.lines()
+                                .find_map(|l| l.strip_prefix(name))
+                                .map(|v| v.trim().to_string())
+                                .unwrap_or_default()
+                        };
+
+                        let timestamp = header("x-webhook-timestamp:");
+                        let signature = header("x-webhook-signature:");
+                        let body = String::from_utf8_lossy(&buf[header_end..header_end + body_len]);
+
+                        let status = {
+                            let mut received = received.lock().unwrap();
+                            received.push((timestamp, signature, body.to_string()));
+
+                            match received.len() <= failures {
+                                true => "500 Internal Server Error",
+                                false => "200 OK",
+                            }
+                        };
+
+                        let res = format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\n\r\n");
+                        socket.write_all(res.as_bytes()).await.ok();
+                    });
+                }
+            }
+        });
+
+        (format!("http://{addr}"), received)
+    }
+
+    fn config(default_url: Option<String>) -> WebhookConfig {
+        WebhookConfig {
+            secret: "secret".to_string(),
+            default_url,
+            merchant_urls: HashMap::new(),
+            max_attempts: 3,
+            backoff: Duration::from_millis(500),
+            allowed_hosts: Vec::new(),
+            // the sink is on loopback
+            allow_private: true,
+            connect_timeout: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    fn request() -> payment::Request {
+        payment::Request {
+            merchant: Some("acme".to_string()),
+            ..testing::request(1)
+        }
+    }
+
+    /// Under a paused clock, so the backoffs take no real time.
+    async fn wait_for(received: &Received, count: usize) {
+        while received.lock().unwrap().len() < count {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_signed_delivery_with_retry() {
+        let (url, received) = spawn_sink(1).await;
+        let webhooks = Webhooks::start(config(Some(url))).unwrap();
+
+        let processor = "default".to_string();
+        webhooks.notify(&request(), Event::Processed { processor });
+
+        wait_for(&received, 2).await;
+        tokio::time::sleep(MAX_BACKOFF).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2, "retried once after the 500");
+
+        let (timestamp, signature, body) = &received[1];
+        assert_eq!(
+            signature,
+            &format!("sha256={}", sign("secret", timestamp, body))
+        );
+
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["event"], "payment.processed");
+        assert_eq!(body["correlationId"], request().correlation_id);
+        assert_eq!(body["processor"], "default");
+        assert_eq!(body["merchant"], "acme");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dead_letter() {
+        let (url, received) = spawn_sink(usize::MAX).await;
+        let webhooks = Webhooks::start(config(Some(url))).unwrap();
+
+        webhooks.notify(&request(), Event::Failed);
+
+        wait_for(&received, 3).await;
+        tokio::time::sleep(MAX_BACKOFF).await;
+
+        // given up after `max_attempts`
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_url() {
+        let mut config = config(Some("http://default".to_string()));
+        config
+            .merchant_urls
+            .insert("acme".to_string(), "http://acme".to_string());
+
+        let webhooks = Webhooks::start(config).unwrap();
+        let inner = webhooks.inner.as_ref().unwrap();
+
+        let mut req = request();
+        assert_eq!(inner.url(&req), Some("http://acme"));
+
+        req.callback_url = Some("http://own".to_string());
+        assert_eq!(inner.url(&req), Some("http://own"));
+
+        req.callback_url = None;
+        req.merchant = None;
+        assert_eq!(inner.url(&req), Some("http://default"));
+
+        assert_eq!(
+            parse_urls("a=http://a, b=http://b").unwrap()["b"],
+            "http://b"
+        );
+        assert!(parse_urls("http://a").is_err());
+        assert_eq!(backoff(Duration::from_millis(500), 1).as_millis(), 500);
+        assert_eq!(backoff(Duration::from_millis(500), 3).as_millis(), 2000);
+        assert_eq!(backoff(Duration::from_millis(500), 30), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let mut config = config(None);
+        config.allow_private = false;
+
+        let webhooks = Webhooks::start(config.clone()).unwrap();
+        let inner = webhooks.inner.as_ref().unwrap();
+
+        assert!(inner.check("https://hooks.example.com/x").is_ok());
+        assert!(inner.check("http://93.184.216.34/x").is_ok());
+
+        for url in [
+            "ftp://hooks.example.com",
+            "http://127.0.0.1:8080",
+            "http://10.0.0.1",
+            "http://192.168.1.1",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]",
+            "http://[::ffff:127.0.0.1]",
+            "http://[fd00::1]",
+        ] {
+            assert!(inner.check(url).is_err(), "{url}");
+        }
+
+        config.allowed_hosts = vec!["hooks.example.com".to_string()];
+        let webhooks = Webhooks::start(config).unwrap();
+        let inner = webhooks.inner.as_ref().unwrap();
+
+        assert!(inner.check("https://hooks.example.com/x").is_ok());
+        assert!(inner.check("https://other.example.com/x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_private_names_refused() {
+        let resolver = PublicResolver {
+            allow_private: false,
+        };
+
+        let name: Name = "localhost".parse().unwrap();
+        assert!(resolver.resolve(name).await.is_err());
+    }
+}